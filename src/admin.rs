@@ -0,0 +1,420 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::audit::{client_info, query_limit, ClientInfo};
+use crate::auth::{active_sessions, authenticate, bearer_token, AuthContext};
+use crate::errors::ApiError;
+use crate::passkeys::passkey_info;
+use crate::roles::{Grants, DEFAULT_ROLE, PERMISSIONS};
+use crate::utils::{constant_time_eq, email_lookup, send_email_change_notice, timestamp, valid_email};
+use crate::*;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+// Ten years, past which a lock may as well be left open ended
+const MAX_LOCK_MINUTES: i64 = 10 * 365 * 24 * 60;
+
+/// Lets in the admin API key, which may do anything and is handed on as None, or a
+/// user whose roles grant `permission`.
+pub fn with_admin(state: Arc<AppState>, permission: &'static str) -> impl Filter<Extract = (Option<AuthContext>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_state(state))
+        .and_then(move |header: Option<String>, state: Arc<AppState>| async move {
+            let token = match header.as_deref().and_then(bearer_token) {
+                Some(token) => token,
+                None => return Err(ApiError::MissingToken.into()),
+            };
+
+            if state.config.admin.api_key.as_deref().is_some_and(|api_key| constant_time_eq(token, api_key)) {
+                return Ok(None);
+            }
+
+            let auth = authenticate(&state, token)?;
+            if !auth.grants.allows(permission) {
+                return Err(ApiError::PermissionDenied(permission).into());
+            }
+            Ok::<Option<AuthContext>, Rejection>(Some(auth))
+        })
+}
+
+// Records an admin action under the caller's guid. The caller was signed in a moment
+// ago, so an account that can't be read is recorded like the API key.
+fn audit_action(state: &AppState, caller: &Option<AuthContext>, client: &ClientInfo, event: AuditEvent, target: u128, reason: Option<String>) {
+    let caller = caller.as_ref().and_then(|auth| state.store.read_user_data(&auth.username).ok()).map(|user_data| user_data.guid);
+    state.audit.record_admin_action(event, caller, target, client, reason);
+}
+
+// Names whoever made an admin request, for the server log
+fn caller_name(caller: &Option<AuthContext>) -> &str {
+    match caller {
+        Some(auth) => &auth.username,
+        None => "admin API key",
+    }
+}
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_admin(state.clone(), "users:read"))
+        .and(warp::query::<AdminUserQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_list_users);
+
+    let view = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_admin(state.clone(), "users:read"))
+        .and(with_state(state.clone()))
+        .and_then(handle_view_user);
+
+    let delete = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_admin(state.clone(), "users:delete"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_delete_user);
+
+    let reset_password = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("reset_password"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "users:manage"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_force_password_reset);
+
+    let revoke_sessions = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("revoke_sessions"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "users:manage"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_revoke_sessions);
+
+    let lock = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("lock"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "users:manage"))
+        .and(client_info(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_lock_user);
+
+    let unlock = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("unlock"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "users:manage"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_unlock_user);
+
+    let change_email = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("email"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "users:manage"))
+        .and(client_info(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_change_email);
+
+    let grant = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("grant"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "roles:manage"))
+        .and(client_info(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_grant);
+
+    let revoke = warp::path("admin")
+        .and(warp::path("users"))
+        .and(warp::path::param::<String>())
+        .and(warp::path("revoke"))
+        .and(warp::post())
+        .and(with_admin(state.clone(), "roles:manage"))
+        .and(client_info(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_revoke);
+
+    let audit = warp::path("admin")
+        .and(warp::path("audit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_admin(state.clone(), "audit:read"))
+        .and(warp::query::<AuditQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_audit);
+
+    list.or(view).or(delete).or(reset_password).or(revoke_sessions).or(lock).or(unlock).or(change_email).or(grant).or(revoke).or(audit)
+}
+
+fn user_view(state: &AppState, user_data: FullUserData) -> Result<AdminUserView, Rejection> {
+    let sessions = active_sessions(state.store.read_sessions(&user_data.username)?, state.config.auth.session_idle_timeout());
+    let sessions = sessions.into_iter().map(|session| SessionInfo {
+        id: session.id,
+        created_at: session.created_at,
+        last_seen_at: session.last_seen_at,
+        expires_at: session.expires_at,
+        device: session.device,
+        ip: session.ip,
+        current: false,
+    }).collect();
+
+    let grants = Grants::of(state, &user_data);
+    Ok(AdminUserView {
+        activity: AccountActivity::from(&user_data),
+        roles: grants.roles,
+        permissions: grants.permissions,
+        guid: Uuid::from_u128(user_data.guid).to_string(),
+        two_factor_enabled: user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled),
+        passkeys: user_data.passkeys.iter().map(passkey_info).collect(),
+        pending_email: user_data.pending_email_change.map(|pending| pending.email),
+        username: user_data.username,
+        email: user_data.email,
+        email_verified: user_data.email_verified,
+        avatar: user_data.avatar,
+        oauth_consents: user_data.oauth_consents,
+        disabled: user_data.disabled,
+        sessions,
+    })
+}
+
+// Signs the user out everywhere: sessions and refresh tokens
+fn revoke_all(state: &AppState, username: &str) -> Result<(), Rejection> {
+    state.store.delete_sessions_for_user(username)?;
+    state.store.revoke_refresh_tokens(username, None)?;
+    Ok(())
+}
+
+async fn handle_list_users(_caller: Option<AuthContext>, query: AdminUserQuery, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let page = query.page.unwrap_or(1);
+    if page < 1 {
+        return Err(ApiError::validation("page", "Pages start at 1").into());
+    }
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+    if !(1..=MAX_PAGE_SIZE).contains(&per_page) {
+        return Err(ApiError::validation("per_page", &format!("Must be between 1 and {}", MAX_PAGE_SIZE)).into());
+    }
+
+    // A page past what the stores can count to would wrap around to an early one
+    let offset = match (page - 1).checked_mul(per_page).filter(|offset| i64::try_from(*offset).is_ok()) {
+        Some(offset) => offset,
+        None => return Err(ApiError::validation("page", "Page is too large").into()),
+    };
+
+    let search = query.search.as_deref().map(str::trim).filter(|search| !search.is_empty());
+    let (users, total) = state.store.list_users(search, offset, per_page)?;
+    let users = users.into_iter().map(|user_data| AdminUserSummary {
+        username: user_data.username,
+        email: user_data.email,
+        email_verified: user_data.email_verified,
+        disabled: user_data.disabled,
+        locked_until: user_data.locked_until,
+        created_at: user_data.created_at,
+        last_login_at: user_data.last_login_at,
+    }).collect();
+
+    Ok(warp::reply::json(&AdminUserPage { users, page, per_page, total }))
+}
+
+async fn handle_view_user(username: String, _caller: Option<AuthContext>, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+async fn handle_delete_user(username: String, caller: Option<AuthContext>, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = state.store.read_user_data(&username)?.guid;
+    state.store.delete_user(&username)?;
+    println!("{} deleted account {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminDeleteAccount, guid, None);
+    Ok(warp::reply::json(&"Account deleted"))
+}
+
+// The old password stops working straight away and a reset code is emailed, so the
+// user has to choose a new password before signing in with one again
+async fn handle_force_password_reset(username: String, caller: Option<AuthContext>, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&username)?;
+    let email = match user_data.email.clone() {
+        Some(email) => email,
+        None => return Err(ApiError::NotFound("email").into()),
+    };
+
+    // An empty hash matches no password
+    user_data.password = String::new();
+    user_data.updated_at = Some(timestamp(Utc::now()));
+    let (username, guid) = (user_data.username.clone(), user_data.guid);
+    state.store.write_user_data(user_data)?;
+    revoke_all(&state, &username)?;
+    audit_action(&state, &caller, &client, AuditEvent::AdminPasswordReset, guid, None);
+
+    send_password_reset_code(&state, &username, &email).await?;
+    println!("{} forced a password reset for {}", caller_name(&caller), username);
+    Ok(warp::reply::json(&"Password reset code sent to email address"))
+}
+
+async fn handle_revoke_sessions(username: String, caller: Option<AuthContext>, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = state.store.read_user_data(&username)?.guid;
+    revoke_all(&state, &username)?;
+    println!("{} revoked every session of {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminRevokeSessions, guid, None);
+    Ok(warp::reply::json(&"All sessions revoked"))
+}
+
+async fn handle_lock_user(username: String, caller: Option<AuthContext>, client: ClientInfo, requset_data: AdminLock, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&username)?;
+    match requset_data.minutes {
+        Some(minutes) if !(1..=MAX_LOCK_MINUTES).contains(&minutes) => {
+            return Err(ApiError::validation("minutes", &format!("Must be between 1 and {}", MAX_LOCK_MINUTES)).into());
+        }
+        Some(minutes) => user_data.locked_until = Some(timestamp(Utc::now() + Duration::minutes(minutes))),
+        None => user_data.disabled = true,
+    }
+    let reason = match requset_data.minutes {
+        Some(minutes) => format!("{} minutes", minutes),
+        None => "disabled".to_string(),
+    };
+
+    let (username, guid) = (user_data.username.clone(), user_data.guid);
+    state.store.write_user_data(user_data)?;
+    revoke_all(&state, &username)?;
+    println!("{} locked account {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminLock, guid, Some(reason));
+
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+// Lifts an admin lock and any lockout after failed logins
+async fn handle_unlock_user(username: String, caller: Option<AuthContext>, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&username)?;
+    user_data.disabled = false;
+    user_data.locked_until = None;
+    user_data.failed_login_count = 0;
+    let guid = user_data.guid;
+    state.store.write_user_data(user_data)?;
+    println!("{} unlocked account {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminUnlock, guid, None);
+
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+// The new address counts as verified, and the old one is told about the change
+async fn handle_change_email(username: String, caller: Option<AuthContext>, client: ClientInfo, requset_data: AdminEmailChange, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    if !valid_email(&requset_data.email) {
+        return Err(ApiError::validation("email", "Invalid email address").into());
+    }
+
+    let user_data = state.store.read_user_data(&username)?;
+    let (old_email, guid) = (user_data.email, user_data.guid);
+    state.store.change_email(&username, &requset_data.email)?;
+    println!("{} changed the email of {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminEmailChange, guid, None);
+
+    let mut user_data = state.store.read_user_data(&username)?;
+    user_data.updated_at = Some(timestamp(Utc::now()));
+    let username = user_data.username.clone();
+    state.store.write_user_data(user_data)?;
+
+    if let Some(old_email) = old_email.filter(|old_email| old_email.to_lowercase() != requset_data.email.to_lowercase()) {
+        if send_email_change_notice(state.mailer.as_ref(), &username, &old_email, &requset_data.email).await.is_err() {
+            println!("Failed to notify {} of email change", old_email);
+        }
+    }
+
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+// Only roles the config knows and permissions routes check can be granted
+fn check_role_change(state: &AppState, change: &RoleChange) -> Result<(), Rejection> {
+    if let Some(role) = change.roles.iter().find(|role| *role == DEFAULT_ROLE || !state.config.roles.permissions.contains_key(*role)) {
+        return Err(ApiError::validation("roles", &format!("No role {} to grant or revoke", role)).into());
+    }
+    if let Some(permission) = change.permissions.iter().find(|permission| !PERMISSIONS.contains(&permission.as_str())) {
+        return Err(ApiError::validation("permissions", &format!("No permission {}", permission)).into());
+    }
+    Ok(())
+}
+
+// E.g. "granted roles: moderator; permissions: users:read"
+fn role_change_reason(change: &str, requset_data: &RoleChange) -> String {
+    format!("{} roles: {}; permissions: {}", change, requset_data.roles.join(", "), requset_data.permissions.join(", "))
+}
+
+async fn handle_grant(username: String, caller: Option<AuthContext>, client: ClientInfo, requset_data: RoleChange, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    check_role_change(&state, &requset_data)?;
+    let reason = role_change_reason("granted", &requset_data);
+    let mut user_data = state.store.read_user_data(&username)?;
+    for role in requset_data.roles {
+        if !user_data.roles.contains(&role) {
+            user_data.roles.push(role);
+        }
+    }
+    for permission in requset_data.permissions {
+        if !user_data.permissions.contains(&permission) {
+            user_data.permissions.push(permission);
+        }
+    }
+
+    let (username, guid) = (user_data.username.clone(), user_data.guid);
+    state.store.write_user_data(user_data)?;
+    println!("{} changed the roles of {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminRoleChange, guid, Some(reason));
+
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+async fn handle_revoke(username: String, caller: Option<AuthContext>, client: ClientInfo, requset_data: RoleChange, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    check_role_change(&state, &requset_data)?;
+    let reason = role_change_reason("revoked", &requset_data);
+    let mut user_data = state.store.read_user_data(&username)?;
+    user_data.roles.retain(|role| !requset_data.roles.contains(role));
+    user_data.permissions.retain(|permission| !requset_data.permissions.contains(permission));
+
+    let (username, guid) = (user_data.username.clone(), user_data.guid);
+    state.store.write_user_data(user_data)?;
+    println!("{} changed the roles of {}", caller_name(&caller), username);
+    audit_action(&state, &caller, &client, AuditEvent::AdminRoleChange, guid, Some(reason));
+
+    let user_data = state.store.read_user_data(&username)?;
+    Ok(warp::reply::json(&user_view(&state, user_data)?))
+}
+
+// Entries for every account, or for the one named by username or email address
+async fn handle_audit(_caller: Option<AuthContext>, query: AuditQuery, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let limit = query_limit(&query)?;
+    let guid = match &query.username {
+        Some(username) => {
+            let username = email_lookup(state.store.as_ref(), username)?;
+            Some(state.store.read_user_data(&username)?.guid)
+        }
+        None => None,
+    };
+
+    let entries = state.audit.query(guid, &query, limit)?;
+    Ok(warp::reply::json(&entries))
+}
@@ -0,0 +1,310 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Duration, NaiveDate, Utc};
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{with_auth, AuthContext};
+use crate::config::{AuditConfig, StorageConfig};
+use crate::errors::{rejection_code, ApiError};
+use crate::utils::{parse_timestamp, timestamp};
+use crate::*;
+
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+/// Where a request came from, as recorded with each audit entry
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+pub fn client_info(state: Arc<AppState>) -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+    client_ip(state)
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(|ip: Option<IpAddr>, user_agent: Option<String>| ClientInfo {
+            ip: ip.map(|ip| ip.to_string()),
+            user_agent,
+        })
+}
+
+/// The client's address: the peer's, or when the peer is a trusted proxy, the last
+/// address in X-Forwarded-For that no trusted proxy added.
+pub fn client_ip(state: Arc<AppState>) -> impl Filter<Extract = (Option<IpAddr>,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(with_state(state))
+        .map(|addr: Option<SocketAddr>, forwarded_for: Option<String>, state: Arc<AppState>| {
+            addr.map(|addr| forwarded_ip(addr.ip(), forwarded_for.as_deref(), &state.config.server.trusted_proxies))
+        })
+}
+
+// Each proxy appends the address it got the request from, so the header is read from
+// the right for as long as the hop that wrote it is trusted. Anything further left
+// came from the client and can't be believed.
+fn forwarded_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let mut ip = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        if !trusted_proxies.contains(&ip) {
+            break;
+        }
+        match hop.trim().parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+    ip
+}
+
+// The file entries are appended to: one sequence of files per UTC day
+struct CurrentFile {
+    date: String,
+    sequence: u32,
+}
+
+/// Append-only log of authentication events, one Json entry per line. Files are named
+/// audit-<date>-<sequence>.jsonl so that sorting them by name sorts them by age.
+pub struct AuditLog {
+    enabled: bool,
+    dir: PathBuf,
+    max_file_size: u64,
+    retention: Duration,
+    current: Mutex<CurrentFile>,
+}
+
+impl AuditLog {
+    pub fn new(config: &AuditConfig, storage: &StorageConfig) -> std::io::Result<AuditLog> {
+        let dir = config.dir.clone().unwrap_or_else(|| storage.data_dir.join("audit"));
+        if config.enabled {
+            fs::create_dir_all(&dir)?;
+        }
+
+        Ok(AuditLog {
+            enabled: config.enabled,
+            dir,
+            max_file_size: config.max_file_size_mb * 1024 * 1024,
+            retention: Duration::days(config.retention_days),
+            current: Mutex::new(CurrentFile { date: String::new(), sequence: 0 }),
+        })
+    }
+
+    /// Records how a request to one of the audited endpoints turned out. Failures are
+    /// recorded with the error code they were answered with.
+    pub fn record<T>(&self, event: AuditEvent, guid: Option<u128>, client: &ClientInfo, result: &Result<T, Rejection>, reason: Option<String>) {
+        let (outcome, reason) = match result {
+            Ok(_) => (AuditOutcome::Success, reason),
+            Err(err) => (AuditOutcome::Failure, Some(rejection_code(err).to_string())),
+        };
+
+        self.append(&AuditEntry {
+            timestamp: timestamp(Utc::now()),
+            event,
+            guid: guid.map(|guid| Uuid::from_u128(guid).to_string()),
+            target: None,
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            outcome,
+            reason,
+        });
+    }
+
+    /// Records something that went well part way through a request, like the session a login
+    /// ends with.
+    pub fn record_success(&self, event: AuditEvent, guid: u128, client: &ClientInfo, reason: &str) {
+        self.record(event, Some(guid), client, &Ok::<(), Rejection>(()), Some(reason.to_string()));
+    }
+
+    /// Records what an admin did to `target`, under the admin's own guid, or none for
+    /// the admin API key.
+    pub fn record_admin_action(&self, event: AuditEvent, caller: Option<u128>, target: u128, client: &ClientInfo, reason: Option<String>) {
+        self.append(&AuditEntry {
+            timestamp: timestamp(Utc::now()),
+            event,
+            guid: caller.map(|guid| Uuid::from_u128(guid).to_string()),
+            target: Some(Uuid::from_u128(target).to_string()),
+            ip: client.ip.clone(),
+            user_agent: client.user_agent.clone(),
+            outcome: AuditOutcome::Success,
+            reason,
+        });
+    }
+
+    // A failed write is reported but never fails the request it describes
+    fn append(&self, entry: &AuditEntry) {
+        if !self.enabled {
+            return;
+        }
+
+        let mut current = self.current.lock().unwrap();
+        let today = Utc::now().format("%Y-%m-%d").to_string();
+        if current.date != today {
+            current.sequence = self.last_sequence(&today);
+            current.date = today;
+            self.prune();
+        }
+
+        let mut path = self.file_path(&current);
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= self.max_file_size) {
+            current.sequence += 1;
+            path = self.file_path(&current);
+        }
+
+        // Entries hold nothing that can fail to serialize
+        let line = serde_json::to_string(entry).unwrap_or_default();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", line));
+        if let Err(err) = result {
+            println!("Failed to write audit entry to {}: {}", path.display(), err);
+        }
+    }
+
+    fn file_path(&self, current: &CurrentFile) -> PathBuf {
+        self.dir.join(format!("audit-{}-{:04}.jsonl", current.date, current.sequence))
+    }
+
+    // Log files by name, oldest first
+    fn files(&self) -> Vec<String> {
+        let mut files: Vec<String> = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries.flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name.starts_with("audit-") && name.ends_with(".jsonl"))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        files.sort();
+        files
+    }
+
+    // The newest file of the day carries on after a restart
+    fn last_sequence(&self, date: &str) -> u32 {
+        let prefix = format!("audit-{}-", date);
+        self.files().iter()
+            .filter_map(|name| name.strip_prefix(&prefix)?.strip_suffix(".jsonl")?.parse().ok())
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Deletes the files from days past the retention period
+    fn prune(&self) {
+        let oldest_kept = (Utc::now() - self.retention).date_naive();
+        for name in self.files() {
+            let date = name.get(6..16).and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+            if date.is_some_and(|date| date < oldest_kept) {
+                if let Err(err) = fs::remove_file(self.dir.join(&name)) {
+                    println!("Failed to remove old audit log {}: {}", name, err);
+                }
+            }
+        }
+    }
+
+    /// Up to `limit` entries newest first, optionally only those of one account (its own
+    /// and admin actions taken on it), an event or an outcome, and only from before `before`.
+    pub fn query(&self, guid: Option<u128>, query: &AuditQuery, limit: usize) -> Result<Vec<AuditEntry>, Rejection> {
+        let before = match query.before.as_deref() {
+            Some(before) => match parse_timestamp(before) {
+                Some(before) => Some(timestamp(before)),
+                None => return Err(ApiError::validation("before", "Must be an RFC 3339 time").into()),
+            },
+            None => None,
+        };
+        let guid = guid.map(|guid| Uuid::from_u128(guid).to_string());
+
+        let mut entries = Vec::new();
+        for name in self.files().iter().rev() {
+            // A file that is gone was pruned in the meantime
+            let contents = match fs::read_to_string(self.dir.join(name)) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+
+            // Lines that don't parse are skipped, like one still being written
+            let matches = contents.lines().rev()
+                .filter_map(|line| serde_json::from_str::<AuditEntry>(line).ok())
+                .filter(|entry| before.as_ref().is_none_or(|before| entry.timestamp < *before))
+                .filter(|entry| guid.is_none() || entry.guid == guid || entry.target == guid)
+                .filter(|entry| query.event.is_none_or(|event| entry.event == event))
+                .filter(|entry| query.outcome.is_none_or(|outcome| entry.outcome == outcome));
+            for entry in matches {
+                if entries.len() == limit {
+                    return Ok(entries);
+                }
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// The limit asked for, checked against the largest page allowed
+pub fn query_limit(query: &AuditQuery) -> Result<usize, Rejection> {
+    let limit = query.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    if !(1..=MAX_QUERY_LIMIT).contains(&limit) {
+        return Err(ApiError::validation("limit", &format!("Must be between 1 and {}", MAX_QUERY_LIMIT)).into());
+    }
+    Ok(limit)
+}
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("account")
+        .and(warp::path("audit"))
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(warp::query::<AuditQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_account_audit)
+}
+
+// The signed in user's own entries
+async fn handle_account_audit(auth: AuthContext, query: AuditQuery, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    if query.username.is_some() {
+        return Err(ApiError::validation("username", "Only the admin API can list other accounts").into());
+    }
+    let limit = query_limit(&query)?;
+
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let entries = state.audit.query(Some(user_data.guid), &query, limit)?;
+    Ok(warp::reply::json(&entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let trusted = [ip("10.0.0.1")];
+
+        assert_eq!(forwarded_ip(ip("203.0.113.5"), Some("198.51.100.7"), &trusted), ip("203.0.113.5"));
+        assert_eq!(forwarded_ip(ip("203.0.113.5"), Some("198.51.100.7"), &[]), ip("203.0.113.5"));
+    }
+
+    #[test]
+    fn takes_the_last_address_no_trusted_proxy_added() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), Some("198.51.100.7"), &trusted), ip("198.51.100.7"));
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), Some("1.1.1.1, 198.51.100.7, 10.0.0.2"), &trusted), ip("198.51.100.7"));
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), Some("garbage, 198.51.100.7"), &trusted), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn stops_at_the_proxy_when_the_header_is_missing_or_broken() {
+        let trusted = [ip("10.0.0.1")];
+
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), None, &trusted), ip("10.0.0.1"));
+        assert_eq!(forwarded_ip(ip("10.0.0.1"), Some("not an address"), &trusted), ip("10.0.0.1"));
+    }
+}
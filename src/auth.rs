@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+use crate::errors::{ApiError, StoreError};
+use crate::roles::Grants;
+use crate::utils::{parse_timestamp, timestamp};
+use crate::{with_state, AccessClaims, AppState, FullUserData, SessionData};
+
+/// Scope of the access tokens handed out at login, which act for the user on every endpoint
+pub const LOGIN_SCOPE: &str = "user";
+
+/// The caller behind a valid `Authorization: Bearer <token>` header, where the
+/// token is a session key or a signed access token.
+#[derive(Debug, Clone)]
+pub struct AuthContext {
+    /// Lowercased username, usable as a store key.
+    pub username: String,
+    /// None for access tokens, which are checked without reading any session.
+    pub session: Option<SessionData>,
+    /// Read from the account for sessions, so changes apply straight away. Access
+    /// tokens carry the grants from when they were issued.
+    pub grants: Grants,
+}
+
+/// Rejects the request unless it carries the key of an active session or a valid
+/// access token, and hands the caller to the handler. Compose it onto every protected route.
+pub fn with_auth(state: Arc<AppState>) -> impl Filter<Extract = (AuthContext,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_state(state))
+        .and_then(|header: Option<String>, state: Arc<AppState>| async move {
+            let token = match header.as_deref().and_then(bearer_token) {
+                Some(token) => token,
+                None => return Err(ApiError::MissingToken.into()),
+            };
+
+            authenticate(&state, token)
+        })
+}
+
+/// Like `with_auth`, but lets requests without an Authorization header through as None.
+pub fn with_optional_auth(state: Arc<AppState>) -> impl Filter<Extract = (Option<AuthContext>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(with_state(state))
+        .and_then(|header: Option<String>, state: Arc<AppState>| async move {
+            let header = match header {
+                Some(header) => header,
+                None => return Ok(None),
+            };
+
+            match bearer_token(&header) {
+                Some(token) => authenticate(&state, token).map(Some),
+                None => Err(ApiError::MissingToken.into()),
+            }
+        })
+}
+
+pub fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    let token = token.trim();
+    if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() {
+        Some(token)
+    } else {
+        None
+    }
+}
+
+pub fn authenticate(state: &AppState, token: &str) -> Result<AuthContext, Rejection> {
+    // Session keys are alphanumeric, JWTs are three dot separated parts
+    if token.contains('.') {
+        return match state.jwt.verify::<AccessClaims>(token) {
+            Some(claims) if claims.scope.split(' ').any(|scope| scope == LOGIN_SCOPE) => {
+                // The token names the user, but only counts while the guid still matches,
+                // so it dies with a rename, deletion or re-registration of the name
+                let username = claims.username.to_lowercase();
+                let user_data = match state.store.read_user_data(&username) {
+                    Ok(user_data) if Uuid::from_u128(user_data.guid).to_string() == claims.sub => user_data,
+                    Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidSession.into()),
+                    Err(err) => return Err(err.into()),
+                };
+                check_account_usable(&user_data)?;
+
+                let grants = Grants { roles: claims.roles, permissions: claims.permissions };
+                Ok(AuthContext { username, session: None, grants })
+            }
+            _ => Err(ApiError::InvalidSession.into()),
+        };
+    }
+
+    let session_key = token;
+    let username = match state.store.session_owner(session_key) {
+        Ok(username) => username,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidSession.into()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let session = authenticate_session(state, &username, session_key)?;
+    let user_data = state.store.read_user_data(&username)?;
+    check_account_usable(&user_data)?;
+    let grants = Grants::of(state, &user_data);
+    Ok(AuthContext { username, session: Some(session), grants })
+}
+
+/// Refuses accounts an admin has disabled or that are locked for now, whichever way the user signs in
+pub fn check_not_locked(user_data: &FullUserData) -> Result<(), Rejection> {
+    if user_data.disabled {
+        return Err(ApiError::AccountDisabled.into());
+    }
+
+    if let Some(locked_until) = user_data.locked_until.as_deref().and_then(parse_timestamp) {
+        let wait = locked_until - Utc::now();
+        if wait > Duration::zero() {
+            return Err(ApiError::AccountLocked(wait.num_seconds().max(1) as u64).into());
+        }
+    }
+
+    Ok(())
+}
+
+// Credentials already handed out stop working while the account is locked or waiting
+// to be deleted; logging in again is the only way to cancel a deletion
+pub fn check_account_usable(user_data: &FullUserData) -> Result<(), Rejection> {
+    check_not_locked(user_data)?;
+    if user_data.deletion_scheduled_at.is_some() {
+        return Err(ApiError::InvalidSession.into());
+    }
+    Ok(())
+}
+
+// Drops sessions that have been idle too long or reached their absolute expiry
+pub fn active_sessions(sessions: Vec<SessionData>, idle_timeout: Duration) -> Vec<SessionData> {
+    let now = Utc::now();
+    sessions.into_iter().filter(|session| {
+        let last_seen = parse_timestamp(&session.last_seen_at);
+        let expires = parse_timestamp(&session.expires_at);
+        match (last_seen, expires) {
+            (Some(last_seen), Some(expires)) => now - last_seen < idle_timeout && now < expires,
+            _ => false,
+        }
+    }).collect()
+}
+
+// Checks the session key against the user's active sessions and marks the session as seen
+fn authenticate_session(state: &AppState, username: &str, session_key: &str) -> Result<SessionData, Rejection> {
+    let sessions = match state.store.read_sessions(username){
+        Ok(sessions) => active_sessions(sessions, state.config.auth.session_idle_timeout()),
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidSession.into()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut session = match sessions.into_iter().find(|session| session.session_key == session_key){
+        Some(session) => session,
+        None => return Err(ApiError::InvalidSession.into()),
+    };
+
+    // Only this session's last_seen_at is written, and a session revoked since it
+    // was read stays revoked
+    session.last_seen_at = timestamp(Utc::now());
+    match state.store.touch_session(username, &session.id, &session.last_seen_at) {
+        Ok(_) => Ok(session),
+        Err(StoreError::NotFound) => Err(ApiError::InvalidSession.into()),
+        Err(err) => Err(err.into()),
+    }
+}
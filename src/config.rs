@@ -0,0 +1,878 @@
+use chrono::Duration;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::mailer::EmailKind;
+use crate::roles::{ADMIN_ROLE, PERMISSIONS};
+
+const USAGE: &str = "Usage: login_user_db [--config <file>] [--bind <address>] [--port <port>] [--data-dir <dir>]
+
+Settings are read from the config file (config.toml if present), then environment
+variables, then these flags, each overriding the previous one.";
+
+// Ten years, far past any sensible setting and well within what chrono can add to the current time
+const MAX_DURATION_SECONDS: i64 = 10 * 365 * 86400;
+// A terabyte, so the size in bytes can't overflow
+const MAX_AUDIT_FILE_SIZE_MB: u64 = 1024 * 1024;
+
+/// Server settings, see `config.example.toml` for every option and its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub mail: MailConfig,
+    pub auth: AuthConfig,
+    pub oauth: OAuthConfig,
+    pub webauthn: WebauthnConfig,
+    pub rate_limit: RateLimitConfig,
+    pub admin: AdminConfig,
+    pub roles: RolesConfig,
+    pub audit: AuditConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    pub port: u16,
+    /// Base address used in links sent by email
+    pub public_url: String,
+    /// Reverse proxies whose X-Forwarded-For header names the client. Requests from any
+    /// other address are taken to come from that address.
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: "127.0.0.1".to_string(),
+            port: 3030,
+            public_url: "http://127.0.0.1:3030".to_string(),
+            trusted_proxies: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    Json,
+    Sqlite,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Json user files for the json backend; other server files like the token secret live here for both
+    pub data_dir: PathBuf,
+    pub sqlite_path: PathBuf,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::Json,
+            data_dir: PathBuf::from("./Json"),
+            sqlite_path: PathBuf::from("./users.db"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MailProvider {
+    Stdout,
+    Outbox,
+    Sendgrid,
+    Smtp,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub provider: MailProvider,
+    pub from: String,
+    pub outbox_dir: PathBuf,
+    pub sendgrid: SendGridConfig,
+    pub smtp: SmtpConfig,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            provider: MailProvider::Stdout,
+            from: "no-reply@gmail.com".to_string(),
+            outbox_dir: PathBuf::from("./outbox"),
+            sendgrid: SendGridConfig::default(),
+            smtp: SmtpConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendGridConfig {
+    pub api_key: Option<String>,
+    /// Dynamic template id for each kind of email
+    pub templates: HashMap<EmailKind, String>,
+}
+
+impl Default for SendGridConfig {
+    fn default() -> Self {
+        let mut templates = HashMap::new();
+        templates.insert(EmailKind::PasswordReset, "d-36dab063ce184e4180e716439b12ac9a".to_string());
+        SendGridConfig { api_key: None, templates }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: Option<String>,
+    pub port: u16,
+    /// STARTTLS; only turn off for a local relay
+    pub tls: bool,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig { host: None, port: 587, tls: true, username: None, password: None }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Logins from clients reporting an older version are refused
+    pub min_client_version: f32,
+    /// Password reset codes: how long they last, how they look, and how many wrong
+    /// guesses use one up
+    pub otp_lifetime_minutes: i64,
+    pub otp_length: usize,
+    pub otp_alphabet: String,
+    pub otp_max_attempts: u32,
+    /// A session ends after this long without a request, or at the absolute timeout regardless of use
+    pub session_idle_timeout_hours: i64,
+    pub session_absolute_timeout_hours: i64,
+    pub email_change_code_lifetime_minutes: i64,
+    pub email_verification_lifetime_hours: i64,
+    pub verification_resend_interval_seconds: i64,
+    pub magic_link_lifetime_minutes: i64,
+    /// Page login links open, with the token appended as ?token=. Defaults to the
+    /// server's own /magic_link/login endpoint.
+    pub magic_link_url: Option<String>,
+    /// Refuse logins until the account's email address has been verified
+    pub require_verified_email: bool,
+    /// Days between a deletion request and the account being erased; logging in before then cancels it
+    pub deletion_grace_days: i64,
+    pub password_hash: PasswordHashConfig,
+    pub access_tokens: AccessTokenConfig,
+    pub two_factor: TwoFactorConfig,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            min_client_version: 0.1,
+            otp_lifetime_minutes: 15,
+            otp_length: 8,
+            otp_alphabet: "0123456789".to_string(),
+            otp_max_attempts: 5,
+            session_idle_timeout_hours: 24 * 7,
+            session_absolute_timeout_hours: 24 * 30,
+            email_change_code_lifetime_minutes: 120,
+            email_verification_lifetime_hours: 24,
+            verification_resend_interval_seconds: 120,
+            magic_link_lifetime_minutes: 15,
+            magic_link_url: None,
+            require_verified_email: false,
+            deletion_grace_days: 14,
+            password_hash: PasswordHashConfig::default(),
+            access_tokens: AccessTokenConfig::default(),
+            two_factor: TwoFactorConfig::default(),
+        }
+    }
+}
+
+impl AuthConfig {
+    pub fn otp_lifetime(&self) -> Duration {
+        Duration::minutes(self.otp_lifetime_minutes)
+    }
+
+    pub fn session_idle_timeout(&self) -> Duration {
+        Duration::hours(self.session_idle_timeout_hours)
+    }
+
+    pub fn session_absolute_timeout(&self) -> Duration {
+        Duration::hours(self.session_absolute_timeout_hours)
+    }
+
+    pub fn email_change_code_lifetime(&self) -> Duration {
+        Duration::minutes(self.email_change_code_lifetime_minutes)
+    }
+
+    pub fn email_verification_lifetime(&self) -> Duration {
+        Duration::hours(self.email_verification_lifetime_hours)
+    }
+
+    pub fn verification_resend_interval(&self) -> Duration {
+        Duration::seconds(self.verification_resend_interval_seconds)
+    }
+
+    pub fn magic_link_lifetime(&self) -> Duration {
+        Duration::minutes(self.magic_link_lifetime_minutes)
+    }
+
+    pub fn deletion_grace(&self) -> Duration {
+        Duration::days(self.deletion_grace_days)
+    }
+}
+
+/// TOTP second factor settings
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TwoFactorConfig {
+    /// Shown next to the account name in authenticator apps
+    pub issuer: String,
+    /// How long the password step of a two-factor login stays valid
+    pub challenge_lifetime_minutes: i64,
+    /// Wrong codes before a challenge stops working and the password has to be entered again
+    pub challenge_max_attempts: u32,
+    pub recovery_codes: i64,
+}
+
+impl Default for TwoFactorConfig {
+    fn default() -> Self {
+        TwoFactorConfig { issuer: "Login_User_DB".to_string(), challenge_lifetime_minutes: 5, challenge_max_attempts: 5, recovery_codes: 10 }
+    }
+}
+
+impl TwoFactorConfig {
+    pub fn challenge_lifetime(&self) -> Duration {
+        Duration::minutes(self.challenge_lifetime_minutes)
+    }
+}
+
+/// Argon2id cost parameters
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordHashConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        PasswordHashConfig { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+/// Signed access tokens and refresh tokens handed out at login alongside the session key
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessTokenConfig {
+    pub enabled: bool,
+    pub lifetime_minutes: i64,
+    pub refresh_lifetime_days: i64,
+}
+
+impl Default for AccessTokenConfig {
+    fn default() -> Self {
+        AccessTokenConfig { enabled: false, lifetime_minutes: 15, refresh_lifetime_days: 30 }
+    }
+}
+
+impl AccessTokenConfig {
+    pub fn lifetime(&self) -> Duration {
+        Duration::minutes(self.lifetime_minutes)
+    }
+
+    pub fn refresh_lifetime(&self) -> Duration {
+        Duration::days(self.refresh_lifetime_days)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    /// Page that signs the user in and shows the consent prompt. Browsers opening
+    /// /oauth/authorize without a token are sent here with the same query string.
+    pub login_url: Option<String>,
+    pub authorization_code_lifetime_seconds: i64,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        OAuthConfig { login_url: None, authorization_code_lifetime_seconds: 600 }
+    }
+}
+
+impl OAuthConfig {
+    pub fn authorization_code_lifetime(&self) -> Duration {
+        Duration::seconds(self.authorization_code_lifetime_seconds)
+    }
+}
+
+/// Passkeys are only offered once `origin` is set, since WebAuthn needs a domain name
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebauthnConfig {
+    /// Origin of the page that runs the WebAuthn calls, e.g. https://example.com.
+    /// Its host is used as the relying party id.
+    pub origin: Option<String>,
+    /// Shown by the browser and authenticator when creating a passkey
+    pub rp_name: String,
+    pub ceremony_timeout_seconds: i64,
+}
+
+impl Default for WebauthnConfig {
+    fn default() -> Self {
+        WebauthnConfig { origin: None, rp_name: "Login_User_DB".to_string(), ceremony_timeout_seconds: 300 }
+    }
+}
+
+impl WebauthnConfig {
+    pub fn ceremony_timeout(&self) -> Duration {
+        Duration::seconds(self.ceremony_timeout_seconds)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Sent as `Authorization: Bearer <api_key>` on /admin requests, which then
+    /// have every permission. Without it only users with the right roles get in.
+    pub api_key: Option<String>,
+    /// Username or email of a verified account given the admin role once, at startup
+    pub bootstrap_admin: Option<String>,
+}
+
+/// Role name -> the permissions it grants. Every account has the "user" role.
+#[derive(Debug, Deserialize)]
+#[serde(transparent)]
+pub struct RolesConfig {
+    pub permissions: HashMap<String, Vec<String>>,
+}
+
+impl Default for RolesConfig {
+    fn default() -> Self {
+        let roles = [
+            ("user", vec!["oauth_clients:create"]),
+            ("support", vec!["users:read", "users:manage"]),
+            ("admin", vec!["users:read", "users:manage", "users:delete", "roles:manage", "audit:read", "oauth_clients:create"]),
+        ];
+        let permissions = roles.into_iter()
+            .map(|(role, permissions)| (role.to_string(), permissions.into_iter().map(str::to_string).collect()))
+            .collect();
+        RolesConfig { permissions }
+    }
+}
+
+/// Limits on login, registration and password reset attempts
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Per client IP, for each limited endpoint
+    pub ip: BucketConfig,
+    /// Per username or email address, for each limited endpoint
+    pub account: BucketConfig,
+    /// Failed passwords in a row before an account is locked
+    pub lockout_after_failures: u32,
+    /// The first lockout's length, doubled for every further failure
+    pub lockout_base_seconds: i64,
+    pub lockout_max_seconds: i64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            ip: BucketConfig { burst: 20, per_minute: 10 },
+            account: BucketConfig { burst: 10, per_minute: 2 },
+            lockout_after_failures: 5,
+            lockout_base_seconds: 60,
+            lockout_max_seconds: 3600,
+        }
+    }
+}
+
+/// The log of registrations, logins, password resets and profile changes
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Where the log files go, defaults to an audit directory in storage.data_dir
+    pub dir: Option<PathBuf>,
+    /// A new file is started every day (UTC) and whenever the current one grows past this
+    pub max_file_size_mb: u64,
+    /// Files older than this are deleted
+    pub retention_days: i64,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            enabled: true,
+            dir: None,
+            max_file_size_mb: 64,
+            retention_days: 90,
+        }
+    }
+}
+
+/// A token bucket: up to `burst` requests at once, then `per_minute` on average
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Config {
+    /// Builds the config from the file, environment and command line arguments (without the program name).
+    pub fn load(args: Vec<String>) -> Result<Config, String> {
+        let flags = parse_flags(args)?;
+
+        let path = flags.get("config").cloned().or(std::env::var("CONFIG_FILE").ok());
+        let mut config = match &path {
+            Some(path) => Config::from_file(path)?,
+            None if fs::metadata("./config.toml").is_ok() => Config::from_file("./config.toml")?,
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.apply_flags(&flags)?;
+        config.validate()?;
+        for warning in config.warnings() {
+            eprintln!("WARNING: {}", warning);
+        }
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Config, String> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(format!("Failed to read config file {}: {}", path, err)),
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => Ok(config),
+            Err(err) => Err(format!("Invalid config file {}: {}", path, err)),
+        }
+    }
+
+    fn apply_env(&mut self) -> Result<(), String> {
+        env_override("BIND_ADDRESS", &mut self.server.bind_address)?;
+        env_override("PORT", &mut self.server.port)?;
+        env_override("PUBLIC_URL", &mut self.server.public_url)?;
+        env_override_list("TRUSTED_PROXIES", &mut self.server.trusted_proxies)?;
+
+        if let Ok(backend) = std::env::var("USER_STORE") {
+            self.storage.backend = match backend.as_str() {
+                "json" => StorageBackend::Json,
+                "sqlite" => StorageBackend::Sqlite,
+                _ => return Err(format!("USER_STORE must be json or sqlite, got {}", backend)),
+            };
+        }
+        env_override("DATA_DIR", &mut self.storage.data_dir)?;
+        env_override("SQLITE_PATH", &mut self.storage.sqlite_path)?;
+
+        if let Ok(provider) = std::env::var("MAILER") {
+            self.mail.provider = match provider.as_str() {
+                "stdout" => MailProvider::Stdout,
+                "outbox" => MailProvider::Outbox,
+                "sendgrid" => MailProvider::Sendgrid,
+                "smtp" => MailProvider::Smtp,
+                _ => return Err(format!("MAILER must be stdout, outbox, sendgrid or smtp, got {}", provider)),
+            };
+        }
+        env_override("MAIL_FROM", &mut self.mail.from)?;
+        env_override("MAIL_OUTBOX_DIR", &mut self.mail.outbox_dir)?;
+        env_override_option("SENDGRID_API_KEY", &mut self.mail.sendgrid.api_key);
+        for (kind, name) in [
+            (EmailKind::PasswordReset, "SENDGRID_PASSWORD_RESET_TEMPLATE"),
+            (EmailKind::EmailChangeCode, "SENDGRID_EMAIL_CHANGE_CODE_TEMPLATE"),
+            (EmailKind::EmailChangeNotice, "SENDGRID_EMAIL_CHANGE_NOTICE_TEMPLATE"),
+            (EmailKind::EmailVerification, "SENDGRID_EMAIL_VERIFICATION_TEMPLATE"),
+            (EmailKind::MagicLink, "SENDGRID_MAGIC_LINK_TEMPLATE"),
+            (EmailKind::AccountDeletionScheduled, "SENDGRID_ACCOUNT_DELETION_SCHEDULED_TEMPLATE"),
+            (EmailKind::AccountDeleted, "SENDGRID_ACCOUNT_DELETED_TEMPLATE"),
+        ] {
+            if let Ok(template_id) = std::env::var(name) {
+                self.mail.sendgrid.templates.insert(kind, template_id);
+            }
+        }
+        env_override_option("SMTP_HOST", &mut self.mail.smtp.host);
+        env_override("SMTP_PORT", &mut self.mail.smtp.port)?;
+        env_override("SMTP_TLS", &mut self.mail.smtp.tls)?;
+        env_override_option("SMTP_USERNAME", &mut self.mail.smtp.username);
+        env_override_option("SMTP_PASSWORD", &mut self.mail.smtp.password);
+
+        env_override("MIN_CLIENT_VERSION", &mut self.auth.min_client_version)?;
+        env_override("OTP_LIFETIME_MINUTES", &mut self.auth.otp_lifetime_minutes)?;
+        env_override("OTP_LENGTH", &mut self.auth.otp_length)?;
+        env_override("OTP_ALPHABET", &mut self.auth.otp_alphabet)?;
+        env_override("OTP_MAX_ATTEMPTS", &mut self.auth.otp_max_attempts)?;
+        env_override("SESSION_IDLE_TIMEOUT_HOURS", &mut self.auth.session_idle_timeout_hours)?;
+        env_override("SESSION_ABSOLUTE_TIMEOUT_HOURS", &mut self.auth.session_absolute_timeout_hours)?;
+        env_override("REQUIRE_VERIFIED_EMAIL", &mut self.auth.require_verified_email)?;
+        env_override_option("MAGIC_LINK_URL", &mut self.auth.magic_link_url);
+        env_override("ACCOUNT_DELETION_GRACE_DAYS", &mut self.auth.deletion_grace_days)?;
+        env_override("ACCESS_TOKENS_ENABLED", &mut self.auth.access_tokens.enabled)?;
+        env_override("ACCESS_TOKEN_LIFETIME_MINUTES", &mut self.auth.access_tokens.lifetime_minutes)?;
+        env_override("REFRESH_TOKEN_LIFETIME_DAYS", &mut self.auth.access_tokens.refresh_lifetime_days)?;
+        env_override("TOTP_ISSUER", &mut self.auth.two_factor.issuer)?;
+
+        env_override_option("OAUTH_LOGIN_URL", &mut self.oauth.login_url);
+        env_override_option("WEBAUTHN_ORIGIN", &mut self.webauthn.origin);
+        env_override_option("ADMIN_API_KEY", &mut self.admin.api_key);
+        env_override_option("BOOTSTRAP_ADMIN", &mut self.admin.bootstrap_admin);
+
+        env_override("RATE_LIMIT_ENABLED", &mut self.rate_limit.enabled)?;
+        env_override("LOCKOUT_AFTER_FAILURES", &mut self.rate_limit.lockout_after_failures)?;
+
+        env_override("AUDIT_LOG_ENABLED", &mut self.audit.enabled)?;
+        if let Ok(dir) = std::env::var("AUDIT_LOG_DIR") {
+            self.audit.dir = Some(PathBuf::from(dir));
+        }
+        env_override("AUDIT_RETENTION_DAYS", &mut self.audit.retention_days)?;
+        Ok(())
+    }
+
+    fn apply_flags(&mut self, flags: &HashMap<String, String>) -> Result<(), String> {
+        if let Some(bind_address) = flags.get("bind") {
+            self.server.bind_address = bind_address.clone();
+        }
+        if let Some(port) = flags.get("port") {
+            self.server.port = match port.parse() {
+                Ok(port) => port,
+                Err(_) => return Err(format!("--port must be a port number, got {}", port)),
+            };
+        }
+        if let Some(data_dir) = flags.get("data-dir") {
+            self.storage.data_dir = PathBuf::from(data_dir);
+        }
+        Ok(())
+    }
+
+    // Settings that work but are only meant for trying the server out locally
+    fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        let local = reqwest::Url::parse(&self.server.public_url)
+            .is_ok_and(|url| matches!(url.host_str(), Some("localhost" | "127.0.0.1" | "[::1]")));
+        if matches!(self.mail.provider, MailProvider::Stdout) && !local {
+            warnings.push(format!(
+                "mail.provider is stdout, so password reset codes, login links and verification links are printed to the console \
+                instead of being emailed. Set mail.provider (or MAILER) to sendgrid or smtp before serving {}.",
+                self.server.public_url
+            ));
+        }
+
+        warnings
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if IpAddr::from_str(&self.server.bind_address).is_err() {
+            errors.push(format!("server.bind_address is not an IP address: {}", self.server.bind_address));
+        }
+        if !self.server.public_url.starts_with("http://") && !self.server.public_url.starts_with("https://") {
+            errors.push(format!("server.public_url must start with http:// or https://: {}", self.server.public_url));
+        }
+
+        match self.mail.provider {
+            MailProvider::Sendgrid if self.mail.sendgrid.api_key.is_none() => {
+                errors.push("mail.sendgrid.api_key is required when mail.provider is sendgrid".to_string())
+            }
+            MailProvider::Smtp if self.mail.smtp.host.is_none() => {
+                errors.push("mail.smtp.host is required when mail.provider is smtp".to_string())
+            }
+            _ => {}
+        }
+
+        let auth = &self.auth;
+        if !auth.min_client_version.is_finite() || auth.min_client_version < 0.0 {
+            errors.push("auth.min_client_version must be a positive number".to_string());
+        }
+        if !(6..=64).contains(&auth.otp_length) {
+            errors.push(format!("auth.otp_length must be between 6 and 64, got {}", auth.otp_length));
+        }
+        let mut alphabet: Vec<char> = auth.otp_alphabet.chars().collect();
+        alphabet.sort();
+        alphabet.dedup();
+        if alphabet.len() < 10 || alphabet.len() != auth.otp_alphabet.chars().count() || !alphabet.iter().all(|c| c.is_ascii_graphic()) {
+            errors.push("auth.otp_alphabet must be at least 10 distinct printable ASCII characters".to_string());
+        }
+        if auth.otp_max_attempts < 1 {
+            errors.push("auth.otp_max_attempts must be a positive number".to_string());
+        }
+        if auth.two_factor.challenge_max_attempts < 1 {
+            errors.push("auth.two_factor.challenge_max_attempts must be a positive number".to_string());
+        }
+        // Lengths of time in seconds, minutes, hours or days
+        for (name, value, unit_seconds) in [
+            ("auth.otp_lifetime_minutes", auth.otp_lifetime_minutes, 60),
+            ("auth.session_idle_timeout_hours", auth.session_idle_timeout_hours, 3600),
+            ("auth.session_absolute_timeout_hours", auth.session_absolute_timeout_hours, 3600),
+            ("auth.email_change_code_lifetime_minutes", auth.email_change_code_lifetime_minutes, 60),
+            ("auth.email_verification_lifetime_hours", auth.email_verification_lifetime_hours, 3600),
+            ("auth.verification_resend_interval_seconds", auth.verification_resend_interval_seconds, 1),
+            ("auth.magic_link_lifetime_minutes", auth.magic_link_lifetime_minutes, 60),
+            ("auth.deletion_grace_days", auth.deletion_grace_days, 86400),
+            ("auth.access_tokens.lifetime_minutes", auth.access_tokens.lifetime_minutes, 60),
+            ("auth.access_tokens.refresh_lifetime_days", auth.access_tokens.refresh_lifetime_days, 86400),
+            ("auth.two_factor.challenge_lifetime_minutes", auth.two_factor.challenge_lifetime_minutes, 60),
+            ("oauth.authorization_code_lifetime_seconds", self.oauth.authorization_code_lifetime_seconds, 1),
+            ("webauthn.ceremony_timeout_seconds", self.webauthn.ceremony_timeout_seconds, 1),
+            ("rate_limit.lockout_base_seconds", self.rate_limit.lockout_base_seconds, 1),
+            ("rate_limit.lockout_max_seconds", self.rate_limit.lockout_max_seconds, 1),
+            ("audit.retention_days", self.audit.retention_days, 86400),
+        ] {
+            if value < 1 {
+                errors.push(format!("{} must be a positive number, got {}", name, value));
+            } else if value > MAX_DURATION_SECONDS / unit_seconds {
+                errors.push(format!("{} can be at most {} (ten years), got {}", name, MAX_DURATION_SECONDS / unit_seconds, value));
+            }
+        }
+        if !(1..=100).contains(&auth.two_factor.recovery_codes) {
+            errors.push(format!("auth.two_factor.recovery_codes must be between 1 and 100, got {}", auth.two_factor.recovery_codes));
+        }
+        if auth.session_idle_timeout_hours > auth.session_absolute_timeout_hours {
+            errors.push("auth.session_idle_timeout_hours can not be longer than auth.session_absolute_timeout_hours".to_string());
+        }
+
+        if let Some(magic_link_url) = &auth.magic_link_url {
+            if !magic_link_url.starts_with("http://") && !magic_link_url.starts_with("https://") {
+                errors.push(format!("auth.magic_link_url must start with http:// or https://: {}", magic_link_url));
+            }
+        }
+
+        // otpauth:// URIs use a colon to separate the issuer from the account name
+        if auth.two_factor.issuer.is_empty() || auth.two_factor.issuer.contains(':') {
+            errors.push(format!("auth.two_factor.issuer must be non-empty and can not contain a colon: {}", auth.two_factor.issuer));
+        }
+
+        if let Some(login_url) = &self.oauth.login_url {
+            if !login_url.starts_with("http://") && !login_url.starts_with("https://") {
+                errors.push(format!("oauth.login_url must start with http:// or https://: {}", login_url));
+            }
+        }
+
+        let rate_limit = &self.rate_limit;
+        for (name, bucket) in [("rate_limit.ip", &rate_limit.ip), ("rate_limit.account", &rate_limit.account)] {
+            if bucket.burst < 1 || bucket.per_minute < 1 {
+                errors.push(format!("{}.burst and {}.per_minute must be positive numbers", name, name));
+            }
+        }
+        if rate_limit.lockout_after_failures < 1 {
+            errors.push("rate_limit.lockout_after_failures must be a positive number".to_string());
+        }
+
+        if !(1..=MAX_AUDIT_FILE_SIZE_MB).contains(&self.audit.max_file_size_mb) {
+            errors.push(format!("audit.max_file_size_mb must be between 1 and {}, got {}", MAX_AUDIT_FILE_SIZE_MB, self.audit.max_file_size_mb));
+        }
+
+        if let Some(origin) = &self.webauthn.origin {
+            if !reqwest::Url::parse(origin).is_ok_and(|origin| origin.domain().is_some()) {
+                errors.push(format!("webauthn.origin must be a URL with a domain name, not an IP address: {}", origin));
+            }
+        }
+
+        if self.admin.api_key.as_ref().is_some_and(|api_key| api_key.len() < 32) {
+            errors.push("admin.api_key must be at least 32 characters long".to_string());
+        }
+
+        for (role, permissions) in &self.roles.permissions {
+            for permission in permissions.iter().filter(|permission| !PERMISSIONS.contains(&permission.as_str())) {
+                errors.push(format!("roles.{} grants unknown permission {}, known ones are {}", role, permission, PERMISSIONS.join(", ")));
+            }
+        }
+        if self.admin.bootstrap_admin.is_some() && !self.roles.permissions.contains_key(ADMIN_ROLE) {
+            errors.push(format!("admin.bootstrap_admin needs a roles.{} role to grant", ADMIN_ROLE));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  {}", errors.join("\n  ")))
+        }
+    }
+}
+
+fn parse_flags(args: Vec<String>) -> Result<HashMap<String, String>, String> {
+    let mut flags = HashMap::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let name = match arg.strip_prefix("--") {
+            Some("help") => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            Some(name @ ("config" | "bind" | "port" | "data-dir")) => name.to_string(),
+            _ => return Err(format!("Unknown argument {}\n\n{}", arg, USAGE)),
+        };
+
+        match args.next() {
+            Some(value) => flags.insert(name, value),
+            None => return Err(format!("--{} needs a value\n\n{}", name, USAGE)),
+        };
+    }
+
+    Ok(flags)
+}
+
+fn env_override<T: FromStr>(name: &str, target: &mut T) -> Result<(), String> {
+    if let Ok(value) = std::env::var(name) {
+        *target = match value.parse() {
+            Ok(value) => value,
+            Err(_) => return Err(format!("Invalid value for {}: {}", name, value)),
+        };
+    }
+    Ok(())
+}
+
+// Comma separated, and an empty value clears the list
+fn env_override_list<T: FromStr>(name: &str, target: &mut Vec<T>) -> Result<(), String> {
+    if let Ok(value) = std::env::var(name) {
+        *target = match value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::parse).collect() {
+            Ok(items) => items,
+            Err(_) => return Err(format!("Invalid value for {}: {}", name, value)),
+        };
+    }
+    Ok(())
+}
+
+fn env_override_option(name: &str, target: &mut Option<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *target = Some(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // The environment is shared by every test thread
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        for (name, value) in vars {
+            std::env::set_var(name, value);
+        }
+        let result = f();
+        for (name, _) in vars {
+            std::env::remove_var(name);
+        }
+        result
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn env_overrides_the_file_and_flags_override_both() {
+        let path = std::env::temp_dir().join(format!("config_test_{}.toml", rand::random::<u64>()));
+        fs::write(&path, "[server]\nbind_address = \"10.0.0.1\"\nport = 4000\n\n[storage]\ndata_dir = \"/from/file\"\n").unwrap();
+
+        let config = with_env(&[("PORT", "5000"), ("DATA_DIR", "/from/env")], || {
+            Config::load(args(&["--config", path.to_str().unwrap(), "--port", "6000"]))
+        })
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.bind_address, "10.0.0.1");
+        assert_eq!(config.storage.data_dir, PathBuf::from("/from/env"));
+        assert_eq!(config.server.port, 6000);
+    }
+
+    #[test]
+    fn reads_settings_from_their_env_names() {
+        let mut config = Config::default();
+        with_env(
+            &[
+                ("MAILER", "outbox"),
+                ("OTP_LENGTH", "10"),
+                ("TRUSTED_PROXIES", "10.0.0.1, ::1"),
+                ("RATE_LIMIT_ENABLED", "false"),
+                ("AUDIT_LOG_DIR", "/var/log/audit"),
+                ("SMTP_HOST", "mail.example.com"),
+            ],
+            || config.apply_env(),
+        )
+        .unwrap();
+
+        assert_eq!(config.mail.provider, MailProvider::Outbox);
+        assert_eq!(config.auth.otp_length, 10);
+        assert_eq!(config.server.trusted_proxies, vec!["10.0.0.1".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert!(!config.rate_limit.enabled);
+        assert_eq!(config.audit.dir, Some(PathBuf::from("/var/log/audit")));
+        assert_eq!(config.mail.smtp.host.as_deref(), Some("mail.example.com"));
+    }
+
+    #[test]
+    fn refuses_env_values_that_do_not_parse() {
+        for (name, value) in [("PORT", "http"), ("TRUSTED_PROXIES", "10.0.0.1,proxy"), ("MAILER", "pigeon")] {
+            let result = with_env(&[(name, value)], || Config::default().apply_env());
+            assert!(result.unwrap_err().contains(name));
+        }
+    }
+
+    #[test]
+    fn refuses_unknown_flags_and_flags_without_values() {
+        assert!(parse_flags(args(&["--verbose"])).unwrap_err().starts_with("Unknown argument --verbose"));
+        assert!(parse_flags(args(&["--port"])).unwrap_err().starts_with("--port needs a value"));
+        assert!(Config::default().apply_flags(&parse_flags(args(&["--port", "70000"])).unwrap()).is_err());
+    }
+
+    #[test]
+    fn accepts_the_defaults() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    fn validation_error(change: impl FnOnce(&mut Config)) -> String {
+        let mut config = Config::default();
+        change(&mut config);
+        config.validate().unwrap_err()
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        assert!(validation_error(|config| config.auth.otp_lifetime_minutes = 0).contains("auth.otp_lifetime_minutes must be a positive number"));
+        assert!(validation_error(|config| config.auth.otp_lifetime_minutes = MAX_DURATION_SECONDS / 60 + 1)
+            .contains("auth.otp_lifetime_minutes can be at most 5256000 (ten years)"));
+        assert!(validation_error(|config| config.rate_limit.lockout_max_seconds = i64::MAX).contains("rate_limit.lockout_max_seconds can be at most"));
+        assert!(validation_error(|config| config.auth.two_factor.recovery_codes = 101).contains("auth.two_factor.recovery_codes must be between 1 and 100"));
+        assert!(validation_error(|config| config.audit.max_file_size_mb = MAX_AUDIT_FILE_SIZE_MB + 1).contains("audit.max_file_size_mb must be between"));
+        assert!(validation_error(|config| config.auth.otp_length = 5).contains("auth.otp_length must be between 6 and 64"));
+        assert!(validation_error(|config| config.server.bind_address = "localhost".to_string()).contains("server.bind_address is not an IP address"));
+    }
+
+    #[test]
+    fn warns_when_the_stdout_mailer_serves_a_public_url() {
+        let mut config = Config::default();
+        assert!(config.warnings().is_empty());
+
+        config.server.public_url = "https://example.com".to_string();
+        let warnings = config.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("mail.provider is stdout"));
+
+        config.mail.provider = MailProvider::Outbox;
+        assert!(config.warnings().is_empty());
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{with_auth, AuthContext};
+use crate::errors::{ApiError, StoreError};
+use crate::password::PasswordCheck;
+use crate::utils::{send_account_deleted, send_deletion_scheduled, timestamp};
+use crate::*;
+
+// How often accounts whose grace period has run out are looked for
+const PURGE_INTERVAL_SECONDS: u64 = 600;
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("delete_account")
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_delete_account)
+}
+
+// Schedules the account for deletion and signs it out everywhere. Logging in again
+// before the grace period ends cancels the deletion.
+async fn handle_delete_account(auth: AuthContext, requset_data: DeleteAccountRequest, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&auth.username)?;
+    if matches!(state.passwords.verify(&requset_data.password, &user_data.password).await, PasswordCheck::Invalid) {
+        return Err(ApiError::InvalidCredentials.into());
+    }
+
+    let deletion_date = Utc::now() + state.config.auth.deletion_grace();
+    user_data.deletion_scheduled_at = Some(timestamp(deletion_date));
+    let email = user_data.email.clone();
+    state.store.write_user_data(user_data)?;
+
+    state.store.delete_sessions_for_user(&auth.username)?;
+    state.store.revoke_refresh_tokens(&auth.username, None)?;
+    println!("Account {} scheduled for deletion on {}", auth.username, timestamp(deletion_date));
+
+    if let Some(email) = email {
+        let deletion_date = deletion_date.format("%Y-%m-%d %H:%M UTC").to_string();
+        if send_deletion_scheduled(state.mailer.as_ref(), &deletion_date, &auth.username, &email).await.is_err() {
+            println!("Failed to send deletion notice to {}", email);
+        }
+    }
+
+    Ok(warp::reply::json(&"Account scheduled for deletion"))
+}
+
+/// Erases every account whose grace period has run out, now and then for as long as the server runs.
+pub async fn purge_scheduled_deletions(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(PURGE_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(err) = purge_due_accounts(&state).await {
+            println!("Failed to purge accounts due for deletion: {:?}", err);
+        }
+    }
+}
+
+// An account that can't be erased is reported and tried again next time, without
+// holding up the others
+async fn purge_due_accounts(state: &AppState) -> Result<(), StoreError> {
+    let now = timestamp(Utc::now());
+    for user_data in state.store.deletions_due(&now)? {
+        match purge_account(state, &user_data.username, &now) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                println!("Failed to delete account {} at the end of its grace period: {:?}", user_data.username, err);
+                continue;
+            }
+        }
+
+        if let Some(email) = user_data.email {
+            if send_account_deleted(state.mailer.as_ref(), &user_data.username, &email).await.is_err() {
+                println!("Failed to send deletion confirmation to {}", email);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// False when the deletion was cancelled in the meantime
+fn purge_account(state: &AppState, username: &str, now: &str) -> Result<bool, StoreError> {
+    // A login may have cancelled the deletion since the accounts were listed
+    let still_due = state.store.read_user_data(username)?
+        .deletion_scheduled_at
+        .is_some_and(|scheduled_at| scheduled_at.as_str() <= now);
+    if !still_due {
+        return Ok(false);
+    }
+
+    state.store.delete_user(username)?;
+    println!("Deleted account {} at the end of its grace period", username);
+    Ok(true)
+}
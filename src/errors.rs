@@ -0,0 +1,241 @@
+use std::convert::Infallible;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use warp::http::header::{HeaderValue, RETRY_AFTER, WWW_AUTHENTICATE};
+use warp::http::StatusCode;
+use warp::{reject, Rejection, Reply};
+
+/// Every way a request can fail. Each variant has a stable `code` that clients
+/// can branch on; the message is for humans and may change.
+#[derive(Debug)]
+pub enum ApiError {
+    /// A field failed validation; `field` is reported back in `details`.
+    Validation { field: &'static str, message: String },
+    InvalidBody(String),
+    InvalidQuery(String),
+    InvalidCredentials,
+    MissingToken,
+    InvalidSession,
+    InvalidCode,
+    InvalidToken,
+    EmailNotVerified,
+    /// Disabled by an admin until they unlock it.
+    AccountDisabled,
+    /// The caller's roles don't grant the named permission.
+    PermissionDenied(&'static str),
+    ClientOutdated,
+    /// The named resource does not exist, e.g. "user" or "session".
+    NotFound(&'static str),
+    RouteNotFound,
+    PasskeysDisabled,
+    MethodNotAllowed,
+    UnsupportedMediaType,
+    PayloadTooLarge,
+    UsernameTaken,
+    EmailTaken,
+    EmailAlreadyVerified,
+    TwoFactorEnabled,
+    TwoFactorNotEnabled,
+    /// Too many attempts, retry after the given number of seconds.
+    RateLimited(u64),
+    /// Locked after failed logins or by an admin, the account unlocks after the given number of seconds.
+    AccountLocked(u64),
+    EmailDelivery,
+    /// Logged server side, never shown to the client.
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+}
+
+impl ApiError {
+    pub fn validation(field: &'static str, message: &str) -> ApiError {
+        ApiError::Validation { field, message: message.to_string() }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Validation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::InvalidBody(_) | ApiError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            ApiError::InvalidCredentials | ApiError::MissingToken | ApiError::InvalidSession | ApiError::InvalidCode | ApiError::InvalidToken => StatusCode::UNAUTHORIZED,
+            ApiError::EmailNotVerified | ApiError::AccountDisabled | ApiError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+            ApiError::ClientOutdated => StatusCode::UPGRADE_REQUIRED,
+            ApiError::NotFound(_) | ApiError::RouteNotFound | ApiError::PasskeysDisabled => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UsernameTaken | ApiError::EmailTaken | ApiError::EmailAlreadyVerified => StatusCode::CONFLICT,
+            ApiError::TwoFactorEnabled | ApiError::TwoFactorNotEnabled => StatusCode::CONFLICT,
+            ApiError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::AccountLocked(_) => StatusCode::LOCKED,
+            ApiError::EmailDelivery => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Validation { .. } => "validation_failed",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::MissingToken => "missing_token",
+            ApiError::InvalidSession => "invalid_session",
+            ApiError::InvalidCode => "invalid_code",
+            ApiError::InvalidToken => "invalid_token",
+            ApiError::EmailNotVerified => "email_not_verified",
+            ApiError::AccountDisabled => "account_disabled",
+            ApiError::PermissionDenied(_) => "permission_denied",
+            ApiError::ClientOutdated => "client_outdated",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::RouteNotFound => "route_not_found",
+            ApiError::PasskeysDisabled => "passkeys_disabled",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::UnsupportedMediaType => "unsupported_media_type",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::UsernameTaken => "username_taken",
+            ApiError::EmailTaken => "email_taken",
+            ApiError::EmailAlreadyVerified => "email_already_verified",
+            ApiError::TwoFactorEnabled => "two_factor_enabled",
+            ApiError::TwoFactorNotEnabled => "two_factor_not_enabled",
+            ApiError::RateLimited(_) => "rate_limited",
+            ApiError::AccountLocked(_) => "account_locked",
+            ApiError::EmailDelivery => "email_delivery_failed",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ApiError::Validation { message, .. } => message.clone(),
+            ApiError::InvalidBody(_) => "Request body is not valid".to_string(),
+            ApiError::InvalidQuery(_) => "Query string is not valid".to_string(),
+            ApiError::InvalidCredentials => "Incorrect username or password for this account".to_string(),
+            ApiError::MissingToken => "Authorization header with a bearer token required".to_string(),
+            ApiError::InvalidSession => "Session is invalid or has expired".to_string(),
+            ApiError::InvalidCode => "Code invalid or expired".to_string(),
+            ApiError::InvalidToken => "Token invalid or expired".to_string(),
+            ApiError::EmailNotVerified => "Email address not verified".to_string(),
+            ApiError::AccountDisabled => "Account has been disabled".to_string(),
+            ApiError::PermissionDenied(_) => "Your roles do not allow this".to_string(),
+            ApiError::ClientOutdated => "Please update application version".to_string(),
+            ApiError::NotFound(resource) => format!("No such {}", resource),
+            ApiError::RouteNotFound => "No such endpoint".to_string(),
+            ApiError::PasskeysDisabled => "Passkeys are not enabled on this server".to_string(),
+            ApiError::MethodNotAllowed => "Method not allowed for this endpoint".to_string(),
+            ApiError::UnsupportedMediaType => "Request body must be Json".to_string(),
+            ApiError::PayloadTooLarge => "Request body is too large".to_string(),
+            ApiError::UsernameTaken => "Username already taken".to_string(),
+            ApiError::EmailTaken => "Email already associated with an account".to_string(),
+            ApiError::EmailAlreadyVerified => "Email already verified".to_string(),
+            ApiError::TwoFactorEnabled => "Two-factor authentication is already enabled".to_string(),
+            ApiError::TwoFactorNotEnabled => "Two-factor authentication is not enabled".to_string(),
+            ApiError::RateLimited(_) => "Too many requests, try again later".to_string(),
+            ApiError::AccountLocked(_) => "Account temporarily locked, try again later".to_string(),
+            ApiError::EmailDelivery => "Failed to send email".to_string(),
+            ApiError::Internal(_) => "Internal server error".to_string(),
+        }
+    }
+
+    pub fn details(&self) -> Option<Value> {
+        match self {
+            ApiError::Validation { field, .. } => Some(json!({ "field": field })),
+            ApiError::InvalidBody(reason) | ApiError::InvalidQuery(reason) => Some(json!({ "reason": reason })),
+            ApiError::NotFound(resource) => Some(json!({ "resource": resource })),
+            ApiError::PermissionDenied(permission) => Some(json!({ "permission": permission })),
+            ApiError::RateLimited(retry_after) | ApiError::AccountLocked(retry_after) => Some(json!({ "retry_after": retry_after })),
+            _ => None,
+        }
+    }
+}
+
+impl reject::Reject for ApiError {}
+
+/// Failures reported by a `UserStore`.
+#[derive(Debug, Clone)]
+pub enum StoreError {
+    NotFound,
+    UsernameTaken,
+    EmailTaken,
+    Io(String),
+}
+
+impl From<StoreError> for ApiError {
+    fn from(err: StoreError) -> ApiError {
+        match err {
+            StoreError::NotFound => ApiError::NotFound("user"),
+            StoreError::UsernameTaken => ApiError::UsernameTaken,
+            StoreError::EmailTaken => ApiError::EmailTaken,
+            StoreError::Io(message) => ApiError::Internal(message),
+        }
+    }
+}
+
+// Lets handlers use `?` on store calls; converted to an `ApiError` when recovering
+impl reject::Reject for StoreError {}
+
+// Maps warp's own rejections onto the same error codes as ours
+fn rejection_to_error(err: &Rejection) -> ApiError {
+    if err.is_not_found() {
+        ApiError::RouteNotFound
+    } else if let Some(err) = err.find::<warp::filters::body::BodyDeserializeError>() {
+        ApiError::InvalidBody(std::error::Error::source(err).map(|source| source.to_string()).unwrap_or_else(|| err.to_string()))
+    } else if let Some(err) = err.find::<warp::reject::InvalidQuery>() {
+        ApiError::InvalidQuery(err.to_string())
+    } else if err.find::<warp::reject::UnsupportedMediaType>().is_some() {
+        ApiError::UnsupportedMediaType
+    } else if err.find::<warp::reject::PayloadTooLarge>().is_some() {
+        ApiError::PayloadTooLarge
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        ApiError::MethodNotAllowed
+    } else {
+        ApiError::Internal(format!("Unhandled rejection: {:?}", err))
+    }
+}
+
+/// The error code a rejection is answered with
+pub fn rejection_code(err: &Rejection) -> &'static str {
+    match (err.find::<ApiError>(), err.find::<StoreError>()) {
+        (Some(error), _) => error.code(),
+        (None, Some(store_error)) => ApiError::from(store_error.clone()).code(),
+        (None, None) => rejection_to_error(err).code(),
+    }
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let fallback;
+    let error = match (err.find::<ApiError>(), err.find::<StoreError>()) {
+        (Some(error), _) => error,
+        (None, Some(store_error)) => {
+            fallback = ApiError::from(store_error.clone());
+            &fallback
+        }
+        (None, None) => {
+            fallback = rejection_to_error(&err);
+            &fallback
+        }
+    };
+
+    if let ApiError::Internal(message) = error {
+        eprintln!("Internal error: {}", message);
+    }
+
+    let body = ErrorBody { code: error.code(), message: error.message(), details: error.details() };
+    let mut response = warp::reply::with_status(warp::reply::json(&body), error.status()).into_response();
+    match error {
+        ApiError::RateLimited(seconds) | ApiError::AccountLocked(seconds) => {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(*seconds));
+        }
+        ApiError::MissingToken | ApiError::InvalidSession => {
+            response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        _ => {}
+    }
+
+    Ok(response)
+}
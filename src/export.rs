@@ -0,0 +1,106 @@
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+use chrono::Utc;
+use uuid::Uuid;
+use warp::http::header::{CONTENT_DISPOSITION, CONTENT_TYPE};
+use warp::{Filter, Rejection, Reply};
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+use crate::auth::{active_sessions, with_auth, AuthContext};
+use crate::errors::ApiError;
+use crate::passkeys::passkey_info;
+use crate::utils::timestamp;
+use crate::*;
+
+const EXPORT_FILE_NAME: &str = "account_data";
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path("account")
+        .and(warp::path("export"))
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(warp::query::<ExportQuery>())
+        .and(with_state(state.clone()))
+        .and_then(handle_export)
+}
+
+async fn handle_export(auth: AuthContext, query: ExportQuery, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let zipped = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("zip") => true,
+        Some(_) => return Err(ApiError::validation("format", "Must be json or zip").into()),
+    };
+
+    let export = data_export(&state, &auth)?;
+    let json = match serde_json::to_vec_pretty(&export) {
+        Ok(json) => json,
+        Err(err) => return Err(ApiError::Internal(format!("Failed to serialize data export: {}", err)).into()),
+    };
+
+    let (body, content_type, extension) = if zipped {
+        (zip_file(&format!("{}.json", EXPORT_FILE_NAME), &json)?, "application/zip", "zip")
+    } else {
+        (json, "application/json", "json")
+    };
+
+    let reply = warp::reply::with_header(body, CONTENT_TYPE, content_type);
+    Ok(warp::reply::with_header(reply, CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", EXPORT_FILE_NAME, extension)))
+}
+
+fn data_export(state: &AppState, auth: &AuthContext) -> Result<DataExport, Rejection> {
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let sessions = active_sessions(state.store.read_sessions(&auth.username)?, state.config.auth.session_idle_timeout());
+    let sessions = sessions.into_iter().map(|session| SessionInfo {
+        current: auth.session.as_ref().is_some_and(|current| current.id == session.id),
+        id: session.id,
+        created_at: session.created_at,
+        last_seen_at: session.last_seen_at,
+        expires_at: session.expires_at,
+        device: session.device,
+        ip: session.ip,
+    }).collect();
+
+    let audit_log = state.audit.query(Some(user_data.guid), &AuditQuery::default(), usize::MAX)?;
+
+    let pending_email_change = user_data.pending_email_change.clone();
+    Ok(DataExport {
+        exported_at: timestamp(Utc::now()),
+        activity: AccountActivity::from(&user_data),
+        sessions,
+        audit_log,
+        email_events: EmailEvents {
+            email_verified: user_data.email_verified,
+            verification_sent_at: user_data.verification_sent_at.clone(),
+            pending_email: pending_email_change.as_ref().map(|pending| pending.email.clone()),
+            pending_email_requested_at: pending_email_change.map(|pending| pending.date),
+        },
+        profile: ExportProfile {
+            guid: Uuid::from_u128(user_data.guid).to_string(),
+            roles: auth.grants.roles.clone(),
+            permissions: auth.grants.permissions.clone(),
+            two_factor_enabled: user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled),
+            passkeys: user_data.passkeys.iter().map(passkey_info).collect(),
+            username: user_data.username,
+            email: user_data.email,
+            avatar: user_data.avatar,
+            oauth_consents: user_data.oauth_consents,
+        },
+    })
+}
+
+// A zip archive holding a single file
+fn zip_file(name: &str, contents: &[u8]) -> Result<Vec<u8>, Rejection> {
+    let result = (|| {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file(name, SimpleFileOptions::default())?;
+        zip.write_all(contents)?;
+        Ok::<_, zip::result::ZipError>(zip.finish()?.into_inner())
+    })();
+
+    match result {
+        Ok(archive) => Ok(archive),
+        Err(err) => Err(ApiError::Internal(format!("Failed to zip data export: {}", err)).into()),
+    }
+}
@@ -0,0 +1,678 @@
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::errors::StoreError;
+use crate::store::UserStore;
+use crate::utils::{hash_token, parse_timestamp, read_from_file, write_file_atomic};
+use crate::{AuthCodeData, FullUserData, OAuthClient, OTPData, RefreshTokenData, SessionData};
+
+const REFRESH_TOKENS_FILE: &str = "refresh_tokens.txt";
+const OAUTH_CLIENTS_FILE: &str = "oauth_clients.txt";
+const AUTH_CODES_FILE: &str = "auth_codes.txt";
+// Hashed session key to lowercased username, so a session is found without reading
+// every user's session file
+const SESSION_INDEX_FILE: &str = "session_index.txt";
+
+// Written before a rename starts and removed once it has finished. If the server
+// stops part way through, the rename is completed on the next startup.
+#[derive(Debug, Deserialize, Serialize)]
+struct PendingRename {
+    username: String,
+    new_username: String,
+}
+
+/// The original on-disk layout: `{root}/user_map.txt` plus one
+/// `{root}/Users/{username}/` directory holding the user, session and otp files.
+/// `{root}/session_index.txt` finds the owner of a session key.
+pub struct JsonStore {
+    root: PathBuf,
+    // Serializes changes that touch the shared user map
+    lock: Mutex<()>,
+}
+
+impl JsonStore {
+    pub fn new(root: impl Into<PathBuf>) -> std::io::Result<JsonStore> {
+        let root = root.into();
+        fs::create_dir_all(root.join("Users"))?;
+
+        let user_map_path = root.join("user_map.txt");
+        if fs::metadata(&user_map_path).is_err() {
+            fs::write(&user_map_path, "{}")?;
+        }
+
+        let store = JsonStore { root, lock: Mutex::new(()) };
+        // Data directories from before the index get one built on the first start
+        if fs::metadata(store.root.join(SESSION_INDEX_FILE)).is_err() {
+            if let Err(err) = store.build_session_index() {
+                return Err(std::io::Error::other(format!("{:?}", err)));
+            }
+        }
+
+        if let Some(pending) = read_from_file(&store.pending_rename_file()) {
+            if let Ok(pending) = serde_json::from_str::<PendingRename>(&pending) {
+                println!("Completing interrupted rename of {} to {}", pending.username, pending.new_username);
+                if let Err(err) = store.finish_rename(&pending) {
+                    return Err(std::io::Error::other(format!("{:?}", err)));
+                }
+            }
+        }
+
+        Ok(store)
+    }
+
+    fn user_dir(&self, username: &str) -> PathBuf {
+        self.root.join("Users").join(username.to_lowercase())
+    }
+
+    fn user_file(&self, username: &str, file_name: &str) -> String {
+        self.user_dir(username).join(file_name).to_string_lossy().to_string()
+    }
+
+    fn user_map_file(&self) -> String {
+        self.root.join("user_map.txt").to_string_lossy().to_string()
+    }
+
+    fn pending_rename_file(&self) -> String {
+        self.root.join("pending_rename.txt").to_string_lossy().to_string()
+    }
+
+    pub fn write_usermap(&self, usermap: &HashMap<String, String>) -> Result<(), StoreError> {
+        let user_map_string = match serde_json::to_string(&usermap) {
+            Ok(user_map_string) => user_map_string,
+            Err(_) => return Err(StoreError::Io("Failed to save updated usermap".to_string())),
+        };
+
+        match write_file_atomic(self.root.join("user_map.txt").as_path(), &user_map_string) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to save updated usermap: {}", err))),
+        }
+    }
+
+    // Records shared by all users (refresh tokens, OAuth clients and codes) live in one
+    // file per kind, keyed by id; callers hold the lock
+    fn read_shared<T: DeserializeOwned>(&self, file_name: &str) -> Result<HashMap<String, T>, StoreError> {
+        let data = match read_from_file(&self.root.join(file_name).to_string_lossy()) {
+            Some(data) => data,
+            None => return Ok(HashMap::new()),
+        };
+
+        match serde_json::from_str(&data) {
+            Ok(records) => Ok(records),
+            Err(err) => Err(StoreError::Io(format!("Failed to read {}: {}", file_name, err))),
+        }
+    }
+
+    fn write_shared<T: Serialize>(&self, file_name: &str, records: &HashMap<String, T>) -> Result<(), StoreError> {
+        let data = match serde_json::to_string(records) {
+            Ok(data) => data,
+            Err(err) => return Err(StoreError::Io(format!("Failed to save {}: {}", file_name, err))),
+        };
+
+        match write_file_atomic(self.root.join(file_name).as_path(), &data) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to save {}: {}", file_name, err))),
+        }
+    }
+
+    fn build_session_index(&self) -> Result<(), StoreError> {
+        let users = match fs::read_dir(self.root.join("Users")) {
+            Ok(users) => users,
+            Err(err) => return Err(StoreError::Io(format!("Failed to list users: {}", err))),
+        };
+
+        let mut index = HashMap::new();
+        for user in users.flatten() {
+            let username = user.file_name().to_string_lossy().to_string();
+            if let Ok(Some(sessions)) = self.read_json::<Vec<SessionData>>(&username, "session_data.txt") {
+                for session in sessions {
+                    index.insert(hash_token(&session.session_key), username.clone());
+                }
+            }
+        }
+        self.write_shared(SESSION_INDEX_FILE, &index)
+    }
+
+    fn read_refresh_tokens(&self) -> Result<HashMap<String, RefreshTokenData>, StoreError> {
+        self.read_shared(REFRESH_TOKENS_FILE)
+    }
+
+    fn write_refresh_tokens(&self, mut tokens: HashMap<String, RefreshTokenData>) -> Result<(), StoreError> {
+        // Expired tokens are dropped whenever the file is rewritten
+        let now = Utc::now();
+        tokens.retain(|_, token| parse_timestamp(&token.expires_at).is_some_and(|expires_at| expires_at > now));
+        self.write_shared(REFRESH_TOKENS_FILE, &tokens)
+    }
+
+    // Every step checks what has already been done, so this can be rerun after a crash
+    fn finish_rename(&self, pending: &PendingRename) -> Result<(), StoreError> {
+        let old_dir = self.user_dir(&pending.username);
+        let new_dir = self.user_dir(&pending.new_username);
+        if fs::metadata(&old_dir).is_ok() && fs::metadata(&new_dir).is_err() {
+            if let Err(err) = fs::rename(&old_dir, &new_dir) {
+                return Err(StoreError::Io(format!("Failed to move user directory: {}", err)));
+            }
+        }
+
+        let mut user_data = self.read_user_data(&pending.new_username)?;
+        user_data.username = pending.new_username.clone();
+        let serialized_user_data = match serde_json::to_string(&user_data) {
+            Ok(user_data) => user_data,
+            Err(err) => return Err(StoreError::Io(format!("Unable to save user data: {}", err))),
+        };
+        if let Err(err) = write_file_atomic(new_dir.join("user_data.txt").as_path(), &serialized_user_data) {
+            return Err(StoreError::Io(format!("Unable to save user data: {}", err)));
+        }
+
+        let mut user_map = self.read_usermap()?;
+        for username in user_map.values_mut() {
+            if username.to_lowercase() == pending.username.to_lowercase() {
+                *username = pending.new_username.to_lowercase();
+            }
+        }
+        self.write_usermap(&user_map)?;
+
+        let mut index: HashMap<String, String> = self.read_shared(SESSION_INDEX_FILE)?;
+        for owner in index.values_mut() {
+            if *owner == pending.username.to_lowercase() {
+                *owner = pending.new_username.to_lowercase();
+            }
+        }
+        self.write_shared(SESSION_INDEX_FILE, &index)?;
+
+        let mut tokens = self.read_refresh_tokens()?;
+        for token in tokens.values_mut() {
+            if token.username == pending.username.to_lowercase() {
+                token.username = pending.new_username.to_lowercase();
+            }
+        }
+        self.write_refresh_tokens(tokens)?;
+
+        let mut clients: HashMap<String, OAuthClient> = self.read_shared(OAUTH_CLIENTS_FILE)?;
+        for client in clients.values_mut() {
+            if client.owner == pending.username.to_lowercase() {
+                client.owner = pending.new_username.to_lowercase();
+            }
+        }
+        self.write_shared(OAUTH_CLIENTS_FILE, &clients)?;
+
+        let mut codes: HashMap<String, AuthCodeData> = self.read_shared(AUTH_CODES_FILE)?;
+        for code in codes.values_mut() {
+            if code.username.to_lowercase() == pending.username.to_lowercase() {
+                code.username = pending.new_username.to_lowercase();
+            }
+        }
+        self.write_shared(AUTH_CODES_FILE, &codes)?;
+
+        match fs::remove_file(self.pending_rename_file()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to clear pending rename: {}", err))),
+        }
+    }
+
+    fn write_json<T: Serialize>(&self, username: &str, file_name: &str, value: &T) -> Result<(), StoreError> {
+        if fs::metadata(self.user_dir(username)).is_err() {
+            return Err(StoreError::NotFound);
+        }
+
+        let serialized = match serde_json::to_string(value) {
+            Ok(serialized) => serialized,
+            Err(err) => return Err(StoreError::Io(format!("Failed to serialize {}: {}", file_name, err))),
+        };
+
+        match fs::write(self.user_file(username, file_name), serialized) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to write {}: {}", file_name, err))),
+        }
+    }
+
+    // Read-modify-write of a user's session list under the store lock; nothing is
+    // written when `change` fails. The index follows the session file, so after a crash
+    // in between it can name a session that is gone, which the caller finds missing.
+    fn update_sessions(&self, username: &str, change: impl FnOnce(&mut Vec<SessionData>) -> Result<(), StoreError>) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        if fs::metadata(self.user_dir(username)).is_err() {
+            return Err(StoreError::NotFound);
+        }
+
+        let mut sessions: Vec<SessionData> = self.read_json(username, "session_data.txt").ok().flatten().unwrap_or_default();
+        let old_keys: HashSet<String> = sessions.iter().map(|session| hash_token(&session.session_key)).collect();
+        change(&mut sessions)?;
+        self.write_json(username, "session_data.txt", &sessions)?;
+
+        let new_keys: HashSet<String> = sessions.iter().map(|session| hash_token(&session.session_key)).collect();
+        if old_keys == new_keys {
+            return Ok(());
+        }
+        let mut index: HashMap<String, String> = self.read_shared(SESSION_INDEX_FILE)?;
+        for key in old_keys.difference(&new_keys) {
+            index.remove(key);
+        }
+        for key in new_keys.difference(&old_keys) {
+            index.insert(key.clone(), username.to_lowercase());
+        }
+        self.write_shared(SESSION_INDEX_FILE, &index)
+    }
+
+    // Ok(None) when the file hasn't been written yet
+    fn read_json<T: DeserializeOwned>(&self, username: &str, file_name: &str) -> Result<Option<T>, StoreError> {
+        let data = match read_from_file(&self.user_file(username, file_name)) {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        match serde_json::from_str(&data) {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => Err(StoreError::Io(format!("Failed to read {}: {}", file_name, err))),
+        }
+    }
+}
+
+impl UserStore for JsonStore {
+    fn create_user(&self, user_data: FullUserData) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        if self.read_user_data(&user_data.username).is_ok() {
+            return Err(StoreError::UsernameTaken);
+        }
+
+        // Check if the username or email exists in the user map
+        let mut user_map = self.read_usermap()?;
+        let email = user_data.email.clone().unwrap_or_default().to_lowercase();
+        if user_map.values().any(|v| v.to_lowercase() == user_data.username.to_lowercase()) {
+            return Err(StoreError::UsernameTaken);
+        }
+        if user_map.contains_key(&email) {
+            return Err(StoreError::EmailTaken);
+        }
+
+        if let Err(err) = fs::create_dir_all(self.user_dir(&user_data.username)) {
+            return Err(StoreError::Io(format!("Failed to create user directory: {}", err)));
+        }
+
+        user_map.insert(email, user_data.username.to_lowercase());
+        self.write_usermap(&user_map)?;
+        self.write_user_data(user_data)
+    }
+
+    fn write_user_data(&self, user_data: FullUserData) -> Result<(), StoreError> {
+        self.write_json(&user_data.username, "user_data.txt", &user_data)
+    }
+
+    fn read_user_data(&self, username: &str) -> Result<FullUserData, StoreError> {
+        match self.read_json(username, "user_data.txt")? {
+            Some(user_data) => Ok(user_data),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn rename_user(&self, username: &str, new_username: &str) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        self.read_user_data(username)?;
+
+        let user_map = self.read_usermap()?;
+        let taken = fs::metadata(self.user_dir(new_username)).is_ok()
+            || user_map.values().any(|v| v.to_lowercase() == new_username.to_lowercase());
+        if taken {
+            return Err(StoreError::UsernameTaken);
+        }
+
+        let pending = PendingRename { username: username.to_string(), new_username: new_username.to_string() };
+        let serialized_pending = match serde_json::to_string(&pending) {
+            Ok(pending) => pending,
+            Err(err) => return Err(StoreError::Io(format!("Failed to start rename: {}", err))),
+        };
+        if let Err(err) = write_file_atomic(Path::new(&self.pending_rename_file()), &serialized_pending) {
+            return Err(StoreError::Io(format!("Failed to start rename: {}", err)));
+        }
+
+        self.finish_rename(&pending)
+    }
+
+    fn change_email(&self, username: &str, email: &str) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut user_data = self.read_user_data(username)?;
+
+        let mut user_map = self.read_usermap()?;
+        if let Some(owner) = user_map.get(&email.to_lowercase()) {
+            if owner.to_lowercase() != username.to_lowercase() {
+                return Err(StoreError::EmailTaken);
+            }
+        }
+
+        user_map.retain(|_, owner| owner.to_lowercase() != username.to_lowercase());
+        user_map.insert(email.to_lowercase(), username.to_lowercase());
+        self.write_usermap(&user_map)?;
+
+        user_data.email = Some(email.to_string());
+        user_data.pending_email_change = None;
+        user_data.email_verified = true;
+        self.write_user_data(user_data)
+    }
+
+    // Reads every user's file, which is fine for the occasional admin request
+    fn list_users(&self, search: Option<&str>, offset: usize, limit: usize) -> Result<(Vec<FullUserData>, usize), StoreError> {
+        let users = match fs::read_dir(self.root.join("Users")) {
+            Ok(users) => users,
+            Err(err) => return Err(StoreError::Io(format!("Failed to list users: {}", err))),
+        };
+
+        let search = search.map(|search| search.to_lowercase());
+        let mut matches: Vec<FullUserData> = users.flatten()
+            .filter_map(|user| self.read_user_data(&user.file_name().to_string_lossy()).ok())
+            .filter(|user_data| match &search {
+                Some(search) => user_data.username.to_lowercase().contains(search)
+                    || user_data.email.as_deref().is_some_and(|email| email.to_lowercase().contains(search)),
+                None => true,
+            })
+            .collect();
+        matches.sort_by_key(|user_data| user_data.username.to_lowercase());
+
+        let total = matches.len();
+        Ok((matches.into_iter().skip(offset).take(limit).collect(), total))
+    }
+
+    fn deletions_due(&self, now: &str) -> Result<Vec<FullUserData>, StoreError> {
+        let users = match fs::read_dir(self.root.join("Users")) {
+            Ok(users) => users,
+            Err(err) => return Err(StoreError::Io(format!("Failed to list users: {}", err))),
+        };
+
+        Ok(users.flatten()
+            .filter_map(|user| self.read_user_data(&user.file_name().to_string_lossy()).ok())
+            .filter(|user_data| user_data.deletion_scheduled_at.as_deref().is_some_and(|scheduled_at| scheduled_at <= now))
+            .collect())
+    }
+
+    fn delete_user(&self, username: &str) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let username = username.to_lowercase();
+        if fs::metadata(self.user_dir(&username)).is_err() {
+            return Err(StoreError::NotFound);
+        }
+
+        let mut user_map = self.read_usermap()?;
+        user_map.retain(|_, owner| owner.to_lowercase() != username);
+        self.write_usermap(&user_map)?;
+
+        let mut tokens = self.read_refresh_tokens()?;
+        tokens.retain(|_, token| token.username != username);
+        self.write_refresh_tokens(tokens)?;
+
+        let mut clients: HashMap<String, OAuthClient> = self.read_shared(OAUTH_CLIENTS_FILE)?;
+        clients.retain(|_, client| client.owner != username);
+        self.write_shared(OAUTH_CLIENTS_FILE, &clients)?;
+
+        let mut codes: HashMap<String, AuthCodeData> = self.read_shared(AUTH_CODES_FILE)?;
+        codes.retain(|_, code| code.username != username && clients.contains_key(&code.client_id));
+        self.write_shared(AUTH_CODES_FILE, &codes)?;
+
+        let mut index: HashMap<String, String> = self.read_shared(SESSION_INDEX_FILE)?;
+        index.retain(|_, owner| *owner != username);
+        self.write_shared(SESSION_INDEX_FILE, &index)?;
+
+        // The user, session and otp files go with the directory
+        match fs::remove_dir_all(self.user_dir(&username)) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to delete user directory: {}", err))),
+        }
+    }
+
+    fn create_session(&self, username: &str, session: SessionData) -> Result<(), StoreError> {
+        self.update_sessions(username, |sessions| {
+            sessions.push(session);
+            Ok(())
+        })
+    }
+
+    fn touch_session(&self, username: &str, session_id: &str, last_seen_at: &str) -> Result<(), StoreError> {
+        self.update_sessions(username, |sessions| match sessions.iter_mut().find(|session| session.id == session_id) {
+            Some(session) => {
+                session.last_seen_at = last_seen_at.to_string();
+                Ok(())
+            }
+            None => Err(StoreError::NotFound),
+        })
+    }
+
+    fn delete_session(&self, username: &str, session_id: &str) -> Result<(), StoreError> {
+        self.update_sessions(username, |sessions| {
+            let session_count = sessions.len();
+            sessions.retain(|session| session.id != session_id);
+            if sessions.len() == session_count {
+                return Err(StoreError::NotFound);
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_sessions_for_user(&self, username: &str) -> Result<(), StoreError> {
+        self.update_sessions(username, |sessions| {
+            sessions.clear();
+            Ok(())
+        })
+    }
+
+    fn delete_inactive_sessions(&self, username: &str, seen_before: &str, now: &str) -> Result<(), StoreError> {
+        self.update_sessions(username, |sessions| {
+            sessions.retain(|session| session.last_seen_at.as_str() >= seen_before && session.expires_at.as_str() > now);
+            Ok(())
+        })
+    }
+
+    fn read_sessions(&self, username: &str) -> Result<Vec<SessionData>, StoreError> {
+        if fs::metadata(self.user_dir(username)).is_err() {
+            return Err(StoreError::NotFound);
+        }
+
+        // Files written before multiple sessions hold a single bare session without
+        // timestamps; those sessions are dropped and the user has to log in again.
+        match self.read_json(username, "session_data.txt") {
+            Ok(Some(sessions)) => Ok(sessions),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    fn session_owner(&self, session_key: &str) -> Result<String, StoreError> {
+        let index: HashMap<String, String> = self.read_shared(SESSION_INDEX_FILE)?;
+        match index.get(&hash_token(session_key)) {
+            Some(username) => Ok(username.clone()),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn passkey_owner(&self, credential_id: &str) -> Result<String, StoreError> {
+        let users = match fs::read_dir(self.root.join("Users")) {
+            Ok(users) => users,
+            Err(err) => return Err(StoreError::Io(format!("Failed to list users: {}", err))),
+        };
+
+        for user in users.flatten() {
+            let username = user.file_name().to_string_lossy().to_string();
+            if let Ok(user_data) = self.read_user_data(&username) {
+                if user_data.passkeys.iter().any(|passkey| passkey.id == credential_id) {
+                    return Ok(username);
+                }
+            }
+        }
+
+        Err(StoreError::NotFound)
+    }
+
+    fn create_refresh_token(&self, token: RefreshTokenData) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut tokens = self.read_refresh_tokens()?;
+        tokens.insert(token.token_hash.clone(), token);
+        self.write_refresh_tokens(tokens)
+    }
+
+    fn read_refresh_token(&self, token_hash: &str) -> Result<RefreshTokenData, StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        match self.read_refresh_tokens()?.remove(token_hash) {
+            Some(token) => Ok(token),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn rotate_refresh_token(&self, token_hash: &str, replacement: RefreshTokenData) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut tokens = self.read_refresh_tokens()?;
+        match tokens.get_mut(token_hash) {
+            Some(token) if !token.used => token.used = true,
+            _ => return Err(StoreError::NotFound),
+        }
+
+        tokens.insert(replacement.token_hash.clone(), replacement);
+        self.write_refresh_tokens(tokens)
+    }
+
+    fn revoke_refresh_tokens(&self, username: &str, family_id: Option<&str>) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut tokens = self.read_refresh_tokens()?;
+        tokens.retain(|_, token| token.username != username.to_lowercase() || family_id.is_some_and(|family_id| token.family_id != family_id));
+        self.write_refresh_tokens(tokens)
+    }
+
+    fn create_oauth_client(&self, client: OAuthClient) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut clients: HashMap<String, OAuthClient> = self.read_shared(OAUTH_CLIENTS_FILE)?;
+        clients.insert(client.client_id.clone(), client);
+        self.write_shared(OAUTH_CLIENTS_FILE, &clients)
+    }
+
+    fn read_oauth_client(&self, client_id: &str) -> Result<OAuthClient, StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut clients: HashMap<String, OAuthClient> = self.read_shared(OAUTH_CLIENTS_FILE)?;
+        match clients.remove(client_id) {
+            Some(client) => Ok(client),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn create_auth_code(&self, code: AuthCodeData) -> Result<(), StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut codes: HashMap<String, AuthCodeData> = self.read_shared(AUTH_CODES_FILE)?;
+        let now = Utc::now();
+        codes.retain(|_, code| parse_timestamp(&code.expires_at).is_some_and(|expires_at| expires_at > now));
+        codes.insert(code.code_hash.clone(), code);
+        self.write_shared(AUTH_CODES_FILE, &codes)
+    }
+
+    fn take_auth_code(&self, code_hash: &str) -> Result<AuthCodeData, StoreError> {
+        let _lock = self.lock.lock().unwrap();
+        let mut codes: HashMap<String, AuthCodeData> = self.read_shared(AUTH_CODES_FILE)?;
+        let code = match codes.remove(code_hash) {
+            Some(code) => code,
+            None => return Err(StoreError::NotFound),
+        };
+
+        self.write_shared(AUTH_CODES_FILE, &codes)?;
+        Ok(code)
+    }
+
+    fn write_otp_data(&self, otp_data: OTPData, username: &str) -> Result<(), StoreError> {
+        self.write_json(username, "otp_data.txt", &otp_data)
+    }
+
+    fn delete_otp_data(&self, username: &str) -> Result<(), StoreError> {
+        match fs::remove_file(self.user_file(username, "otp_data.txt")) {
+            Ok(_) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(StoreError::Io(format!("Failed to delete otp_data.txt: {}", err))),
+        }
+    }
+
+    fn read_otp_data(&self, username: &str) -> Result<OTPData, StoreError> {
+        match self.read_json(username, "otp_data.txt")? {
+            Some(otp_data) => Ok(otp_data),
+            None => Err(StoreError::NotFound),
+        }
+    }
+
+    fn read_usermap(&self) -> Result<HashMap<String, String>, StoreError> {
+        let hash_map_str = match read_from_file(&self.user_map_file()) {
+            Some(hash_map_str) => hash_map_str,
+            None => return Err(StoreError::Io("Could not find usermap".to_string())),
+        };
+
+        match serde_json::from_str(&hash_map_str) {
+            Ok(data) => Ok(data),
+            Err(err) => Err(StoreError::Io(format!("Failed to deserialize usermap: {}", err))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_user(username: &str, email: &str) -> FullUserData {
+        serde_json::from_value(serde_json::json!({
+            "username": username,
+            "guid": 7,
+            "email": email,
+            "avatar": null,
+            "password": "hash",
+        }))
+        .unwrap()
+    }
+
+    fn test_session(id: &str, session_key: &str) -> SessionData {
+        SessionData {
+            id: id.to_string(),
+            session_key: session_key.to_string(),
+            created_at: "2024-03-31T01:30:00Z".to_string(),
+            last_seen_at: "2024-03-31T01:30:00Z".to_string(),
+            expires_at: "2024-04-30T01:30:00Z".to_string(),
+            device: None,
+            ip: None,
+        }
+    }
+
+    fn test_store() -> (JsonStore, PathBuf) {
+        let root = std::env::temp_dir().join(format!("json_store_{}", rand::random::<u64>()));
+        (JsonStore::new(&root).unwrap(), root)
+    }
+
+    #[test]
+    fn finds_sessions_through_the_index() {
+        let (store, root) = test_store();
+        store.create_user(test_user("Bob", "bob@x.io")).unwrap();
+        store.create_session("bob", test_session("first", "key-1")).unwrap();
+        store.create_session("bob", test_session("second", "key-2")).unwrap();
+        assert_eq!(store.session_owner("key-1").unwrap(), "bob");
+
+        store.delete_session("bob", "first").unwrap();
+        assert!(matches!(store.session_owner("key-1"), Err(StoreError::NotFound)));
+        assert_eq!(store.session_owner("key-2").unwrap(), "bob");
+
+        store.rename_user("bob", "Robert").unwrap();
+        assert_eq!(store.session_owner("key-2").unwrap(), "robert");
+
+        store.delete_sessions_for_user("robert").unwrap();
+        assert!(matches!(store.session_owner("key-2"), Err(StoreError::NotFound)));
+
+        store.create_session("robert", test_session("third", "key-3")).unwrap();
+        store.delete_user("robert").unwrap();
+        assert!(matches!(store.session_owner("key-3"), Err(StoreError::NotFound)));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn builds_the_index_for_older_data_directories() {
+        let (store, root) = test_store();
+        store.create_user(test_user("Bob", "bob@x.io")).unwrap();
+        store.create_session("bob", test_session("first", "key-1")).unwrap();
+        drop(store);
+
+        fs::remove_file(root.join(SESSION_INDEX_FILE)).unwrap();
+        let store = JsonStore::new(&root).unwrap();
+        assert_eq!(store.session_owner("key-1").unwrap(), "bob");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
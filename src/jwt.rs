@@ -0,0 +1,165 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use crypto_hash::{digest, Algorithm as HashAlgorithm};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
+use rand::rngs::OsRng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::Path;
+
+use crate::utils::write_secret_file;
+
+#[derive(Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
+
+/// Signs and checks EdDSA (Ed25519) JWTs. The public half of the key is published
+/// as a JWKS so other services can check tokens without calling us.
+pub struct JwtSigner {
+    signing_key: SigningKey,
+    key_id: String,
+}
+
+impl JwtSigner {
+    /// Reads the base64 encoded private key from `path`, creating a new key pair on first start.
+    pub fn load_or_create(path: &Path) -> std::io::Result<JwtSigner> {
+        let signing_key = match fs::read_to_string(path) {
+            Ok(secret) => {
+                let secret: [u8; 32] = match URL_SAFE_NO_PAD.decode(secret.trim()).ok().and_then(|secret| secret.try_into().ok()) {
+                    Some(secret) => secret,
+                    None => return Err(std::io::Error::other(format!("Invalid signing key in {}", path.display()))),
+                };
+                SigningKey::from_bytes(&secret)
+            }
+            Err(_) => {
+                let signing_key = SigningKey::generate(&mut OsRng);
+                write_secret_file(path, &URL_SAFE_NO_PAD.encode(signing_key.to_bytes()))?;
+                signing_key
+            }
+        };
+
+        // Derived from the public key so it changes whenever the key does
+        let public_key = signing_key.verifying_key().to_bytes();
+        let key_id = URL_SAFE_NO_PAD.encode(&digest(HashAlgorithm::SHA256, &public_key)[..12]);
+        Ok(JwtSigner { signing_key, key_id })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> String {
+        let header = Header { alg: "EdDSA".to_string(), typ: "JWT".to_string(), kid: self.key_id.clone() };
+        let header = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap_or_default());
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap_or_default());
+
+        let signing_input = format!("{}.{}", header, claims);
+        let signature = URL_SAFE_NO_PAD.encode(self.signing_key.sign(signing_input.as_bytes()).to_bytes());
+        format!("{}.{}", signing_input, signature)
+    }
+
+    /// Returns the claims if the token was signed by this key and its `exp` has not passed.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let (signing_input, signature) = token.rsplit_once('.')?;
+        let (header, claims) = signing_input.split_once('.')?;
+
+        let header: Header = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+        if header.alg != "EdDSA" || header.kid != self.key_id {
+            return None;
+        }
+
+        let signature = Signature::from_slice(&URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+        self.signing_key.verifying_key().verify(signing_input.as_bytes(), &signature).ok()?;
+
+        let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(claims).ok()?).ok()?;
+        if claims.get("exp")?.as_i64()? < Utc::now().timestamp() {
+            return None;
+        }
+
+        serde_json::from_value(claims).ok()
+    }
+
+    /// The public key as a JSON Web Key Set.
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": [{
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "alg": "EdDSA",
+                "use": "sig",
+                "kid": self.key_id,
+                "x": URL_SAFE_NO_PAD.encode(self.signing_key.verifying_key().to_bytes()),
+            }]
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer() -> JwtSigner {
+        let path = std::env::temp_dir().join(format!("jwt_signing_key_{}", rand::random::<u64>()));
+        let signer = JwtSigner::load_or_create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        signer
+    }
+
+    fn claims(exp: i64) -> Value {
+        json!({ "sub": "alice", "exp": exp })
+    }
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let jwt = signer();
+        let claims = claims(Utc::now().timestamp() + 60);
+        assert_eq!(jwt.verify::<Value>(&jwt.sign(&claims)), Some(claims));
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_keys() {
+        let jwt = signer();
+        let other = signer();
+        let token = other.sign(&claims(Utc::now().timestamp() + 60));
+        assert_eq!(jwt.verify::<Value>(&token), None);
+
+        // Another key can't pass itself off as ours by borrowing our key id
+        let impostor = JwtSigner { signing_key: other.signing_key, key_id: jwt.key_id.clone() };
+        let token = impostor.sign(&claims(Utc::now().timestamp() + 60));
+        assert_eq!(jwt.verify::<Value>(&token), None);
+    }
+
+    #[test]
+    fn rejects_expired_tokens_and_tokens_without_expiry() {
+        let jwt = signer();
+        assert_eq!(jwt.verify::<Value>(&jwt.sign(&claims(Utc::now().timestamp() - 10))), None);
+        assert_eq!(jwt.verify::<Value>(&jwt.sign(&json!({ "sub": "alice" }))), None);
+    }
+
+    #[test]
+    fn rejects_unsigned_tokens() {
+        let jwt = signer();
+        let token = jwt.sign(&claims(Utc::now().timestamp() + 60));
+        let (header, rest) = token.split_once('.').unwrap();
+        let (payload, _) = rest.split_once('.').unwrap();
+
+        let unsigned = Header { alg: "none".to_string(), typ: "JWT".to_string(), kid: jwt.key_id.clone() };
+        let unsigned = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&unsigned).unwrap());
+        assert_eq!(jwt.verify::<Value>(&format!("{}.{}.", unsigned, payload)), None);
+        assert_eq!(jwt.verify::<Value>(&format!("{}.{}.", header, payload)), None);
+    }
+
+    #[test]
+    fn keeps_the_key_it_creates() {
+        let path = std::env::temp_dir().join(format!("jwt_signing_key_{}", rand::random::<u64>()));
+        let created = JwtSigner::load_or_create(&path).unwrap();
+        let loaded = JwtSigner::load_or_create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let claims = claims(Utc::now().timestamp() + 60);
+        assert_eq!(loaded.verify::<Value>(&created.sign(&claims)), Some(claims));
+        assert_eq!(loaded.jwks()["keys"][0]["kid"], created.key_id);
+    }
+}
@@ -0,0 +1,258 @@
+use async_trait::async_trait;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::{MailConfig, MailProvider};
+use crate::{EmailAddress, Personalization, SendGridEmail};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmailKind {
+    PasswordReset,
+    EmailChangeCode,
+    EmailChangeNotice,
+    EmailVerification,
+    MagicLink,
+    AccountDeletionScheduled,
+    AccountDeleted,
+}
+
+/// A transactional email: which message to send, who to and the values it is filled in with.
+#[derive(Debug, Serialize)]
+pub struct Email {
+    pub kind: EmailKind,
+    pub to: String,
+    pub data: HashMap<String, String>,
+}
+
+impl Email {
+    pub fn new(kind: EmailKind, to: &str) -> Email {
+        Email { kind, to: to.to_string(), data: HashMap::new() }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> Email {
+        self.data.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    fn value(&self, key: &str) -> &str {
+        self.data.get(key).map(String::as_str).unwrap_or_default()
+    }
+
+    // Plain text version used by every transport that doesn't have its own templates
+    pub fn render(&self) -> (String, String) {
+        let greeting = format!("Hi {},\n\n", self.value("username"));
+        match self.kind {
+            EmailKind::PasswordReset => (
+                "Your password reset code".to_string(),
+                format!("{}Use this code to reset your password: {}\n", greeting, self.value("otp")),
+            ),
+            EmailKind::EmailChangeCode => (
+                "Confirm your new email address".to_string(),
+                format!("{}Use this code to confirm {} as your new email address: {}\n", greeting, self.value("email"), self.value("code")),
+            ),
+            EmailKind::EmailChangeNotice => (
+                "Your email address is being changed".to_string(),
+                format!(
+                    "{}A change of your account's email address to {} was requested. If this wasn't you, reset your password.\n",
+                    greeting,
+                    self.value("new_email")
+                ),
+            ),
+            EmailKind::EmailVerification => (
+                "Verify your email address".to_string(),
+                format!("{}Follow this link to verify your email address: {}\n", greeting, self.value("link")),
+            ),
+            EmailKind::MagicLink => (
+                "Your login link".to_string(),
+                format!("{}Follow this link to log in: {}\nIt works once. If you didn't ask for it, you can ignore this email.\n", greeting, self.value("link")),
+            ),
+            EmailKind::AccountDeletionScheduled => (
+                "Your account will be deleted".to_string(),
+                format!(
+                    "{}Your account and all of its data will be deleted on {}. Log in before then to keep it.\n",
+                    greeting,
+                    self.value("deletion_date")
+                ),
+            ),
+            EmailKind::AccountDeleted => (
+                "Your account has been deleted".to_string(),
+                format!("{}Your account and all of its data have been deleted.\n", greeting),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), String>;
+}
+
+/// Sends through the SendGrid API using one dynamic template per kind of email.
+pub struct SendGridMailer {
+    pub api_key: String,
+    pub sender: String,
+    pub template_ids: HashMap<EmailKind, String>,
+}
+
+#[async_trait]
+impl Mailer for SendGridMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let template_id = match self.template_ids.get(&email.kind) {
+            Some(template_id) => template_id,
+            None => return Err(format!("No SendGrid template configured for {:?}", email.kind)),
+        };
+
+        let body = SendGridEmail {
+            personalizations: vec![Personalization {
+                to: vec![EmailAddress {
+                    email: email.to.clone(),
+                }],
+                dynamic_template_data: email.data.clone(),
+            }],
+            from: EmailAddress {
+                email: self.sender.clone(),
+            },
+            template_id: template_id.clone(),
+        };
+
+        let body = match serde_json::to_string(&body) {
+            Ok(body) => body,
+            Err(err) => return Err(format!("Failed to serialize email: {}", err)),
+        };
+
+        let response = Client::new()
+            .post("https://api.sendgrid.com/v3/mail/send")
+            .bearer_auth(&self.api_key)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match response {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                eprintln!("Error sending email: {}", err);
+                Err(err.to_string())
+            }
+        }
+    }
+}
+
+/// Sends plain text emails through an SMTP relay.
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender: Mailbox,
+}
+
+impl SmtpMailer {
+    /// Connects with STARTTLS, or without any encryption when `tls` is false (local relays only).
+    pub fn new(host: &str, port: u16, tls: bool, username: Option<String>, password: Option<String>, sender: &str) -> Result<SmtpMailer, String> {
+        let sender: Mailbox = match sender.parse() {
+            Ok(sender) => sender,
+            Err(err) => return Err(format!("Invalid sender address {}: {}", sender, err)),
+        };
+
+        let builder = if tls {
+            match AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host) {
+                Ok(builder) => builder,
+                Err(err) => return Err(format!("Invalid SMTP host {}: {}", host, err)),
+            }
+        } else {
+            AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+        };
+
+        let builder = match (username, password) {
+            (Some(username), Some(password)) => builder.credentials(Credentials::new(username, password)),
+            _ => builder,
+        };
+
+        Ok(SmtpMailer { transport: builder.port(port).build(), sender })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let to: Mailbox = match email.to.parse() {
+            Ok(to) => to,
+            Err(err) => return Err(format!("Invalid recipient {}: {}", email.to, err)),
+        };
+
+        let (subject, body) = email.render();
+        let message = match Message::builder().from(self.sender.clone()).to(to).subject(subject).body(body) {
+            Ok(message) => message,
+            Err(err) => return Err(format!("Failed to build email: {}", err)),
+        };
+
+        match self.transport.send(message).await {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                eprintln!("Error sending email: {}", err);
+                Err(err.to_string())
+            }
+        }
+    }
+}
+
+/// Development transport: writes each email as a Json file into a directory, or
+/// prints it to stdout when no directory is given. Nothing leaves the machine.
+pub struct OutboxMailer {
+    pub directory: Option<PathBuf>,
+}
+
+#[async_trait]
+impl Mailer for OutboxMailer {
+    async fn send(&self, email: &Email) -> Result<(), String> {
+        let (subject, body) = email.render();
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => {
+                println!("To: {}\nSubject: {}\n\n{}", email.to, subject, body);
+                return Ok(());
+            }
+        };
+
+        let message = serde_json::json!({
+            "kind": email.kind,
+            "to": email.to,
+            "subject": subject,
+            "body": body,
+            "data": email.data,
+        });
+
+        let file_name = format!("{}-{:?}.json", chrono::Utc::now().format("%Y%m%d%H%M%S%f"), email.kind);
+        if let Err(err) = fs::create_dir_all(directory) {
+            return Err(format!("Failed to create outbox directory: {}", err));
+        }
+
+        match fs::write(directory.join(file_name), message.to_string()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("Failed to write email to outbox: {}", err)),
+        }
+    }
+}
+
+pub fn create_mailer(config: &MailConfig) -> Result<Box<dyn Mailer>, String> {
+    match config.provider {
+        MailProvider::Sendgrid => Ok(Box::new(SendGridMailer {
+            api_key: config.sendgrid.api_key.clone().unwrap_or_default(),
+            sender: config.from.clone(),
+            template_ids: config.sendgrid.templates.clone(),
+        })),
+        MailProvider::Smtp => {
+            let smtp = &config.smtp;
+            let host = smtp.host.clone().unwrap_or_default();
+            Ok(Box::new(SmtpMailer::new(&host, smtp.port, smtp.tls, smtp.username.clone(), smtp.password.clone(), &config.from)?))
+        }
+        MailProvider::Outbox => Ok(Box::new(OutboxMailer { directory: Some(config.outbox_dir.clone()) })),
+        MailProvider::Stdout => Ok(Box::new(OutboxMailer { directory: None })),
+    }
+}
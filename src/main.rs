@@ -1,311 +1,1046 @@
 mod utils;
 mod models;
+mod store;
+mod json_store;
+mod sqlite_store;
+mod password;
+mod tokens;
+mod mailer;
+mod config;
+mod errors;
+mod auth;
+mod jwt;
+mod oauth;
+mod two_factor;
+mod passkeys;
+mod rate_limit;
+mod admin;
+mod roles;
+mod deletion;
+mod export;
+mod audit;
 
-use chrono::{Local, DateTime, Duration};
-use crypto_hash::{hex_digest, Algorithm};
+use chrono::{Duration, Utc};
 use rand::Rng;
 use uuid::Uuid;
 use std::collections::HashMap;
 use std::convert::Infallible;
-use std::fs;
-use tokio;
-use warp::{reject, Filter, Rejection, Reply};
+use std::net::IpAddr;
+use std::sync::Arc;
+use warp::{Filter, Rejection, Reply};
 use utils::*;
 use models::*;
+use store::UserStore;
+use json_store::JsonStore;
+use sqlite_store::SqliteStore;
+use password::{PasswordCheck, PasswordHashing};
+use tokens::TokenSigner;
+use mailer::{create_mailer, Mailer};
+use config::{Config, StorageBackend};
+use errors::{handle_rejection, ApiError, StoreError};
+use auth::{check_account_usable, check_not_locked, with_auth, AuthContext, LOGIN_SCOPE};
+use jwt::JwtSigner;
+use passkeys::Passkeys;
+use rate_limit::{count_failed_login, json_limited_by_account, limit_ip, RateLimiter};
+use roles::{bootstrap_admin, Grants};
+use audit::{client_info, AuditLog, ClientInfo};
 
-const APP_VERSION: &'static f32 = &0.1;
+pub struct AppState {
+    pub config: Config,
+    pub store: Box<dyn UserStore>,
+    pub passwords: PasswordHashing,
+    pub tokens: TokenSigner,
+    pub jwt: JwtSigner,
+    pub mailer: Box<dyn Mailer>,
+    /// None unless webauthn.origin is configured
+    pub passkeys: Option<Passkeys>,
+    pub rate_limiter: RateLimiter,
+    pub audit: AuditLog,
+}
 
 #[tokio::main]
 async fn main() {
-    if !fs::metadata("./Json").is_ok() {
-        fs::create_dir("./Json").expect("Failed to create Json directory");
+    let config = match Config::load(std::env::args().skip(1).collect()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = std::fs::create_dir_all(&config.storage.data_dir) {
+        eprintln!("Failed to create data directory {}: {}", config.storage.data_dir.display(), err);
+        std::process::exit(1);
     }
-    if !fs::metadata("./Json/Users").is_ok() {
-        fs::create_dir("./Json/Users").expect("Failed to create User directory");
+
+    let store: Box<dyn UserStore> = match config.storage.backend {
+        StorageBackend::Sqlite => Box::new(SqliteStore::open(&config.storage.sqlite_path).expect("Failed to open SQLite database")),
+        StorageBackend::Json => Box::new(JsonStore::new(&config.storage.data_dir).expect("Failed to create Json directory")),
+    };
+    let hash_config = &config.auth.password_hash;
+    let passwords = PasswordHashing::new(hash_config.memory_kib, hash_config.iterations, hash_config.parallelism).expect("Invalid password hash settings");
+    let tokens = TokenSigner::load_or_create(&config.storage.data_dir.join("token_secret.txt")).expect("Failed to load token secret");
+    let jwt = JwtSigner::load_or_create(&config.storage.data_dir.join("jwt_signing_key.txt")).expect("Failed to load signing key");
+    let mailer = create_mailer(&config.mail).expect("Failed to set up mailer");
+    let passkeys = Passkeys::new(&config.webauthn).expect("Invalid WebAuthn settings");
+    let audit = AuditLog::new(&config.audit, &config.storage).expect("Failed to create audit log directory");
+    let state = Arc::new(AppState { config, store, passwords, tokens, jwt, mailer, passkeys, rate_limiter: RateLimiter::new(), audit });
+    if let Err(err) = bootstrap_admin(&state) {
+        eprintln!("Failed to grant the bootstrap admin role: {:?}", err);
+        std::process::exit(1);
     }
 
-    add_routes().await;
+    tokio::spawn(deletion::purge_scheduled_deletions(state.clone()));
+    add_routes(state).await;
+}
+
+fn with_state(state: Arc<AppState>) -> impl Filter<Extract = (Arc<AppState>,), Error = Infallible> + Clone {
+    warp::any().map(move || state.clone())
 }
 
 async fn handle_get_health() -> Result<impl Reply, Rejection> {
-    return Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::OK));
-}
-
-async fn handle_custom_rejection(err: Rejection) -> std::result::Result<impl Reply, Infallible> {
-    if let Some(custom_error) = err.find::<CustomRejection>() {
-        // Handle the custom rejection and return a 400 Bad Request response
-        let response = warp::reply::with_status(
-            warp::reply::html(format!("Bad Request: {:?}", custom_error)),
-            warp::http::StatusCode::BAD_REQUEST,
-        );
-        Ok(response)
-    } else {
-        // For other rejections, return a generic 500 Internal Server Error response
-        Ok(warp::reply::with_status(
-            warp::reply::html("Internal Server Error".to_string()),
-            warp::http::StatusCode::INTERNAL_SERVER_ERROR,
-        ))
-    }
-}
-
-async fn handle_register(user_data: RegisterUser) -> Result<impl Reply, Rejection> {
-    match read_user_data(&user_data.username.to_lowercase()){
-        Ok(_) => return Err(warp::reject::custom(CustomRejection("Username exists".to_string()))),
-        Err(_) => {},
-    };
+    Ok(warp::reply::with_status(warp::reply(), warp::http::StatusCode::OK))
+}
 
-    // Read the existing user map
-    let mut user_map: HashMap<String, String> = match read_usermap(){
-        Ok(user_map) => user_map,
-        Err(err) => return Err(warp::reject::custom(CustomRejection(err))),
-    };
+// The account a username or email address belongs to, for audit entries
+fn account_guid(state: &AppState, login: &str) -> Option<u128> {
+    email_lookup(state.store.as_ref(), login)
+        .and_then(|username| state.store.read_user_data(&username))
+        .ok()
+        .map(|user_data| user_data.guid)
+}
 
-    // Check if the username or email exists in the user map
-    if user_map.contains_key(&user_data.username.to_lowercase()) || user_map.values().any(|v| v.to_lowercase() == user_data.email.to_lowercase()){
-        return Err(reject::custom(CustomRejection("Username or email already associated with an account".to_string())));
-    } else {
-        user_map.insert(user_data.email.to_lowercase().to_string(), user_data.username.to_lowercase().to_string());
-         match write_usermap(&user_map){
-            Ok(_) => {},
-            Err(err) => return Err(warp::reject::custom(CustomRejection(err))),
-        };
+async fn handle_register(user_data: RegisterUser, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let username = user_data.username.clone();
+    let result = register_account(user_data, state.clone()).await;
+    // A failed registration may name someone else's account
+    let guid = if result.is_ok() { account_guid(&state, &username) } else { None };
+    state.audit.record(AuditEvent::Register, guid, &client, &result, None);
+    result
+}
+
+async fn register_account(user_data: RegisterUser, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    if !valid_username(&user_data.username) {
+        return Err(ApiError::validation("username", "Invalid username").into());
+    }
+
+    if !valid_email(&user_data.email) {
+        return Err(ApiError::validation("email", "Invalid email address").into());
     }
 
-    let mut rng = rand::thread_rng();
-    let guid = Uuid::from_u128(rng.gen()).as_u128();
-    let password_hash = hex_digest(Algorithm::SHA256, user_data.password.as_bytes());
+    let guid = Uuid::from_u128(rand::thread_rng().gen()).as_u128();
+    let password_hash = match state.passwords.hash(&user_data.password).await {
+        Ok(password_hash) => password_hash,
+        Err(err) => return Err(ApiError::Internal(err).into()),
+    };
+    let now = timestamp(Utc::now());
     let full_user_data = FullUserData {
         username: user_data.username.clone(),
         password: password_hash,
-        email: Some(user_data.email),
+        email: Some(user_data.email.clone()),
         guid,
         avatar: None,
+        pending_email_change: None,
+        email_verified: false,
+        verification_sent_at: Some(now.clone()),
+        oauth_consents: HashMap::new(),
+        two_factor: None,
+        passkeys: Vec::new(),
+        magic_link_id: None,
+        failed_login_count: 0,
+        locked_until: None,
+        disabled: false,
+        roles: Vec::new(),
+        permissions: Vec::new(),
+        created_at: Some(now.clone()),
+        updated_at: Some(now.clone()),
+        last_login_at: None,
+        last_login_ip: None,
+        password_changed_at: Some(now),
+        deletion_scheduled_at: None,
+        login_challenge: None,
     };
 
-    match write_user_data(full_user_data){
-        Ok(_) => {
-            let response = LoginResponse { session_key: String::new(), username: user_data.username};
-            return Ok(warp::reply::json(&response))
-        }
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Internal Error01".to_string()))),
+    state.store.create_user(full_user_data)?;
+
+    // The account exists either way, a failed send can be retried through /resend_verification
+    if send_verification_email(&state, &user_data.username, &user_data.email).await.is_err() {
+        println!("Failed to send verification email to {}", user_data.email);
+    }
+
+    let response = LoginResponse { session_key: String::new(), username: user_data.username, tokens: None };
+    Ok(warp::reply::json(&response))
+}
+
+async fn send_verification_email(state: &AppState, username: &str, email: &str) -> Result<(), String> {
+    let claims = EmailVerificationClaims { username: username.to_lowercase(), email: email.to_lowercase() };
+    let token = state.tokens.sign("verify_email", &claims, state.config.auth.email_verification_lifetime());
+    let link = format!("{}/verify_email?token={}", state.config.server.public_url.trim_end_matches('/'), token);
+
+    send_email_verification(state.mailer.as_ref(), &link, username, email).await
+}
+
+async fn handle_verify_email(req: VerifyEmail, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let claims: EmailVerificationClaims = match state.tokens.verify("verify_email", &req.token){
+        Some(claims) => claims,
+        None => return Err(ApiError::InvalidToken.into()),
+    };
+
+    let mut user_data: FullUserData = match state.store.read_user_data(&claims.username){
+        Ok(user_data) => user_data,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
     };
+
+    // The link only counts for the address it was sent to
+    let current_email = user_data.email.clone().unwrap_or_default();
+    if current_email.to_lowercase() != claims.email {
+        return Err(ApiError::InvalidToken.into());
+    }
+
+    user_data.email_verified = true;
+    state.store.write_user_data(user_data)?;
+    Ok(warp::reply::json(&"Email verified"))
 }
 
-async fn handle_login(login: LoginRequest) -> Result<impl Reply, Rejection> {
-    let password_hash = hex_digest(Algorithm::SHA256, &login.password.as_bytes());
-    let username = match email_lookup(&login.username){
+async fn handle_resend_verification(req: ResendVerification, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let username = match email_lookup(state.store.as_ref(), &req.email){
         Ok(username) => username,
-        Err(err) => return Err(warp::reject::custom(CustomRejection(format!("{:?}", err)))),
+        Err(StoreError::NotFound) => return Err(ApiError::NotFound("email").into()),
+        Err(err) => return Err(err.into()),
     };
-    
-    let user_data: FullUserData = match read_user_data(&username) {
+
+    let mut user_data: FullUserData = state.store.read_user_data(&username)?;
+    if user_data.email_verified {
+        return Err(ApiError::EmailAlreadyVerified.into());
+    }
+
+    if let Some(sent_at) = user_data.verification_sent_at.as_deref().and_then(parse_timestamp) {
+        let wait = state.config.auth.verification_resend_interval() - (Utc::now() - sent_at);
+        if wait > Duration::zero() {
+            return Err(ApiError::RateLimited(wait.num_seconds().max(1) as u64).into());
+        }
+    }
+
+    user_data.verification_sent_at = Some(timestamp(Utc::now()));
+    let email = user_data.email.clone().unwrap_or_default();
+    let username = user_data.username.clone();
+    state.store.write_user_data(user_data)?;
+
+    match send_verification_email(&state, &username, &email).await {
+        Ok(_) => Ok(warp::reply::json(&"Verification email sent")),
+        Err(_) => Err(ApiError::EmailDelivery.into()),
+    }
+}
+
+async fn handle_login(login: LoginRequest, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = account_guid(&state, &login.username);
+    let result = password_login(login, &client, state.clone()).await;
+    // Successes are recorded by start_session, and challenges by two_factor_challenge
+    if result.is_err() {
+        state.audit.record(AuditEvent::Login, guid, &client, &result, None);
+    }
+    result
+}
+
+async fn password_login(login: LoginRequest, client: &ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    if login.version < state.config.auth.min_client_version {
+        return Err(ApiError::ClientOutdated.into());
+    }
+
+    // Unknown accounts look the same as a wrong password
+    let user_data = email_lookup(state.store.as_ref(), &login.username)
+        .and_then(|username| state.store.read_user_data(&username));
+    let mut user_data: FullUserData = match user_data {
         Ok(user_data) => user_data,
-        Err(_) => return Err(warp::reject::custom(CustomRejection(format!("Incorrect username or password for this account.")))),
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidCredentials.into()),
+        Err(err) => return Err(err.into()),
+    };
+    let username = user_data.username.clone();
+    let user_data_guid = user_data.guid;
+    let two_factor_methods = two_factor_methods(&state, &user_data);
+
+    // Locked accounts are refused without checking the password, so guessing gets nowhere
+    check_not_locked(&user_data)?;
+
+    let password_check = state.passwords.verify(&login.password, &user_data.password).await;
+    let user_data_verified = user_data.email_verified;
+    if matches!(password_check, PasswordCheck::Invalid) {
+        count_failed_login(&state.config.rate_limit, &mut user_data);
+        state.store.write_user_data(user_data)?;
+        return Err(ApiError::InvalidCredentials.into());
+    }
+
+    // The failed login count is only cleared by start_session, so wrong second factor
+    // codes keep counting toward the lockout however often the password is entered
+    if let PasswordCheck::NeedsRehash = password_check {
+        // Upgrade legacy or outdated hashes now that we have the plaintext
+        match state.passwords.hash(&login.password).await {
+            Ok(password_hash) => {
+                user_data.password = password_hash;
+                if state.store.write_user_data(user_data).is_err() {
+                    println!("Failed to update login state for {}", username);
+                }
+            }
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    if state.config.auth.require_verified_email && !user_data_verified {
+        return Err(ApiError::EmailNotVerified.into());
+    }
+
+    // The password only earns a challenge, the session comes from /2fa/login with a
+    // code or from /passkeys/login with a passkey
+    if !two_factor_methods.is_empty() {
+        let challenge = two_factor_challenge(&state, username, user_data_guid, two_factor_methods, login.device, client, "password")?;
+        return Ok(warp::reply::json(&challenge));
+    }
+
+    let response = start_session(&state, &username, user_data_guid, login.device, client, "password")?;
+    Ok(warp::reply::json(&response))
+}
+
+// Second factors the account has set up, any of which can finish a login
+fn two_factor_methods(state: &AppState, user_data: &FullUserData) -> Vec<String> {
+    let mut methods = Vec::new();
+    if user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled) {
+        methods.push("totp".to_string());
+    }
+    if state.passkeys.is_some() && !user_data.passkeys.is_empty() {
+        methods.push("passkey".to_string());
+    }
+    methods
+}
+
+// `first_factor` is how the user got this far, for the audit log. Issuing a challenge
+// voids any earlier one.
+fn two_factor_challenge(state: &AppState, username: String, guid: u128, methods: Vec<String>, device: Option<String>, client: &ClientInfo, first_factor: &str) -> Result<TwoFactorChallenge, Rejection> {
+    let challenge_id = random_string(32);
+    let mut user_data = state.store.read_user_data(&username)?;
+    user_data.login_challenge = Some(LoginChallenge { id: challenge_id.clone(), failed_attempts: 0 });
+    state.store.write_user_data(user_data)?;
+    state.audit.record_success(AuditEvent::SecondFactorRequired, guid, client, first_factor);
+
+    let claims = TwoFactorChallengeClaims { username, guid, challenge_id, device };
+    let lifetime = state.config.auth.two_factor.challenge_lifetime();
+    Ok(TwoFactorChallenge {
+        two_factor_required: true,
+        methods,
+        challenge_token: state.tokens.sign(two_factor::CHALLENGE_PURPOSE, &claims, lifetime),
+        expires_in: lifetime.num_seconds(),
+    })
+}
+
+// Creates a session for a user who has passed every login check, and records the login
+// along with the `method` that finished it
+fn start_session(state: &AppState, username: &str, guid: u128, device: Option<String>, client: &ClientInfo, method: &str) -> Result<LoginResponse, Rejection> {
+    let mut user_data = state.store.read_user_data(username)?;
+    check_not_locked(&user_data)?;
+
+    let now = Utc::now();
+    state.store.delete_inactive_sessions(username, &timestamp(now - state.config.auth.session_idle_timeout()), &timestamp(now))?;
+
+    let session_key = random_string(32);
+    let session_id = Uuid::from_u128(rand::thread_rng().gen()).to_string();
+    state.store.create_session(username, SessionData {
+        id: session_id.clone(),
+        session_key: session_key.clone(),
+        created_at: timestamp(now),
+        last_seen_at: timestamp(now),
+        expires_at: timestamp(now + state.config.auth.session_absolute_timeout()),
+        device,
+        ip: client.ip.clone(),
+    })?;
+
+    // A session clears the failed login count and spends any second factor challenge
+    user_data.failed_login_count = 0;
+    user_data.locked_until = None;
+    user_data.login_challenge = None;
+    user_data.last_login_at = Some(timestamp(now));
+    user_data.last_login_ip = client.ip.clone();
+    if user_data.deletion_scheduled_at.take().is_some() {
+        println!("Login cancelled the scheduled deletion of {}", username);
+    }
+    let grants = Grants::of(state, &user_data);
+    state.store.write_user_data(user_data)?;
+
+    // The refresh token family shares the session's id, so revoking the session revokes both
+    let mut tokens = None;
+    if state.config.auth.access_tokens.enabled {
+        let (refresh_token, refresh_token_data) = new_refresh_token(state, username, &session_id);
+        state.store.create_refresh_token(refresh_token_data)?;
+        tokens = Some(token_response(state, guid, username, &grants, refresh_token));
+    }
+
+    state.audit.record_success(AuditEvent::Login, guid, client, method);
+    Ok(LoginResponse { session_key, username: username.to_string(), tokens })
+}
+
+fn new_refresh_token(state: &AppState, username: &str, family_id: &str) -> (String, RefreshTokenData) {
+    let refresh_token = random_string(48);
+    let now = Utc::now();
+    let refresh_token_data = RefreshTokenData {
+        token_hash: hash_token(&refresh_token),
+        family_id: family_id.to_string(),
+        username: username.to_lowercase(),
+        created_at: timestamp(now),
+        expires_at: timestamp(now + state.config.auth.access_tokens.refresh_lifetime()),
+        used: false,
+    };
+
+    (refresh_token, refresh_token_data)
+}
+
+// Signs a short lived access token, returning it with its lifetime in seconds
+// Grants are only passed for the user's own login tokens, OAuth clients never act with them
+fn issue_access_token(state: &AppState, guid: u128, username: &str, scope: &str, aud: Option<String>, grants: Option<&Grants>) -> (String, i64) {
+    let now = Utc::now();
+    let lifetime = state.config.auth.access_tokens.lifetime();
+    let claims = AccessClaims {
+        iss: state.config.server.public_url.trim_end_matches('/').to_string(),
+        sub: Uuid::from_u128(guid).to_string(),
+        username: username.to_string(),
+        scope: scope.to_string(),
+        aud,
+        roles: grants.map(|grants| grants.roles.clone()).unwrap_or_default(),
+        permissions: grants.map(|grants| grants.permissions.clone()).unwrap_or_default(),
+        iat: now.timestamp(),
+        exp: (now + lifetime).timestamp(),
     };
 
-    if login.version < APP_VERSION.to_owned() {
-        return Err(reject::custom(CustomRejection("Please update application version".to_string())));
+    (state.jwt.sign(&claims), lifetime.num_seconds())
+}
+
+fn token_response(state: &AppState, guid: u128, username: &str, grants: &Grants, refresh_token: String) -> TokenResponse {
+    let (access_token, expires_in) = issue_access_token(state, guid, username, LOGIN_SCOPE, None, Some(grants));
+    TokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        refresh_token,
     }
+}
 
-    if &user_data.password == &password_hash {
-        let mut rng = rand::thread_rng();
-        let characters = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
-        let session_key: String = (0..32).map(|_| {
-                let index = rng.gen_range(0..characters.len());
-                characters.chars().nth(index).unwrap()
-            })
-            .collect();
+async fn handle_refresh_token(req: RefreshRequest, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let token_hash = hash_token(&req.refresh_token);
+    let token = match state.store.read_refresh_token(&token_hash){
+        Ok(token) => token,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
+    };
 
-        let session_data = SessionData {
-            session_key: session_key.clone(),
-        };
+    // A used token coming back means it was copied. Whoever holds its successor may
+    // be the thief, so the whole family is revoked and the user has to log in again.
+    if token.used {
+        println!("Refresh token reused for {}, revoking token family {}", token.username, token.family_id);
+        state.store.revoke_refresh_tokens(&token.username, Some(&token.family_id))?;
+        return Err(ApiError::InvalidToken.into());
+    }
 
-        match write_sesion_data(session_data, &username){
-            Ok(_) => {},
-            Err(_) => return Err(reject::custom(CustomRejection("Unable to save session data".to_string()))),
-        };
+    let expired = match parse_timestamp(&token.expires_at) {
+        Some(expires_at) => Utc::now() >= expires_at,
+        None => true,
+    };
+    if expired {
+        return Err(ApiError::InvalidToken.into());
+    }
+
+    let user_data: FullUserData = match state.store.read_user_data(&token.username){
+        Ok(user_data) => user_data,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
+    };
+    check_account_usable(&user_data)?;
 
-        return Ok(warp::reply::json(&LoginResponse {session_key, username}));
-    } else {
-        return Err(reject::custom(CustomRejection("Incorrect username or password for this account.".to_string())));
+    let (refresh_token, replacement) = new_refresh_token(&state, &token.username, &token.family_id);
+    match state.store.rotate_refresh_token(&token_hash, replacement) {
+        Ok(_) => {}
+        // Another request rotated it first, which is reuse as well
+        Err(StoreError::NotFound) => {
+            state.store.revoke_refresh_tokens(&token.username, Some(&token.family_id))?;
+            return Err(ApiError::InvalidToken.into());
+        }
+        Err(err) => return Err(err.into()),
     }
+
+    let grants = Grants::of(&state, &user_data);
+    Ok(warp::reply::json(&token_response(&state, user_data.guid, &user_data.username, &grants, refresh_token)))
+}
+
+async fn handle_jwks(state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&state.jwt.jwks()))
+}
+
+async fn handle_get_me(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data: FullUserData = state.store.read_user_data(&auth.username)?;
+    let user = UserData {
+        username: user_data.username,
+        email: user_data.email,
+        email_verified: user_data.email_verified,
+        avatar: user_data.avatar,
+        pending_email: user_data.pending_email_change.map(|pending| pending.email),
+        two_factor_enabled: user_data.two_factor.is_some_and(|two_factor| two_factor.enabled),
+    };
+
+    Ok(warp::reply::json(&user))
 }
 
-async fn handle_user_data_retrieval(requset_data: UserDataRequest) -> Result<impl Reply, Rejection> {
-    let session_data = match read_session_data(&requset_data.username){
-        Ok(session_data) => session_data,
-        Err(_) => return Err(reject::custom(CustomRejection("Can not read authentication key".to_string()))),
+async fn handle_user_data_update(auth: AuthContext, requset_data: UserDataUpdate, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = state.store.read_user_data(&auth.username).ok().map(|user_data| user_data.guid);
+    let changed: Vec<&str> = [
+        ("username", requset_data.new_username.is_some()),
+        ("email", requset_data.email.is_some()),
+        ("avatar", requset_data.avatar.is_some()),
+    ].into_iter().filter(|(_, changed)| *changed).map(|(field, _)| field).collect();
+    let reason = if changed.is_empty() { None } else { Some(format!("changed {}", changed.join(", "))) };
+
+    let result = update_user_data(auth, requset_data, state.clone()).await;
+    state.audit.record(AuditEvent::ProfileUpdate, guid, &client, &result, reason);
+    result
+}
+
+async fn update_user_data(auth: AuthContext, requset_data: UserDataUpdate, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data: FullUserData = state.store.read_user_data(&auth.username)?;
+
+    // Every field is checked before anything is written, so a request with one bad field changes nothing
+    if let Some(new_username) = &requset_data.new_username {
+        if !valid_username(new_username) {
+            return Err(ApiError::validation("new_username", "Invalid username").into());
+        }
+    }
+    if let Some(avatar) = &requset_data.avatar {
+        if !valid_avatar(avatar) {
+            return Err(ApiError::validation("avatar", "Invalid avatar").into());
+        }
+    }
+    let current_email = user_data.email.clone().unwrap_or_default();
+    if let Some(email) = &requset_data.email {
+        if email.to_lowercase() != current_email.to_lowercase() {
+            if !valid_email(email) {
+                return Err(ApiError::validation("email", "Invalid email address").into());
+            }
+
+            if email_lookup(state.store.as_ref(), email).is_ok() {
+                return Err(ApiError::EmailTaken.into());
+            }
+        }
+    }
+
+    if let Some(new_username) = requset_data.new_username {
+        // A change in case only keeps the same directory and key, so nothing needs moving
+        if new_username.to_lowercase() != auth.username {
+            state.store.rename_user(&auth.username, &new_username)?;
+        }
+        user_data.username = new_username;
+    }
+    if let Some(avatar) = requset_data.avatar {
+        user_data.avatar = Some(avatar);
+    }
+    user_data.updated_at = Some(timestamp(Utc::now()));
+
+    // A new email address only replaces the current one once the code sent to it is confirmed
+    let mut email_change_code = None;
+    if let Some(email) = requset_data.email {
+        if email.to_lowercase() == current_email.to_lowercase() {
+            user_data.email = Some(email);
+        } else {
+            let code = random_digits(6);
+            user_data.pending_email_change = Some(PendingEmailChange {
+                email,
+                code_hash: state.tokens.hash(&code),
+                date: timestamp(Utc::now()),
+                attempts: 0,
+            });
+            email_change_code = Some(code);
+        }
+    }
+
+    let user = UserData {
+        username: user_data.username.clone(),
+        email: user_data.email.clone(),
+        email_verified: user_data.email_verified,
+        avatar: user_data.avatar.clone(),
+        pending_email: user_data.pending_email_change.as_ref().map(|pending| pending.email.clone()),
+        two_factor_enabled: user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled),
     };
 
-    if session_data.session_key != requset_data.session_key {
-        return Err(reject::custom(CustomRejection("Incorrect authentication key".to_string())))
-    } else {
-        let user_data: FullUserData = match read_user_data(&requset_data.username){
-            Ok(user_data) => user_data,
-            Err(_) => return Err(reject::custom(CustomRejection("Unable to read to user data".to_string()))),
-        };
+    state.store.write_user_data(user_data)?;
 
-        let user = UserData {
-            username: user_data.username,
-            email: user_data.email,
-            avatar: user_data.avatar,
-        };
+    if let (Some(code), Some(new_email)) = (email_change_code, &user.pending_email) {
+        if send_email_change_code(state.mailer.as_ref(), &code, &user.username, new_email).await.is_err() {
+            return Err(ApiError::EmailDelivery.into());
+        }
 
-        return Ok(warp::reply::json(&user))
+        if let Some(old_email) = &user.email {
+            if send_email_change_notice(state.mailer.as_ref(), &user.username, old_email, new_email).await.is_err() {
+                println!("Failed to notify {} of email change", old_email);
+            }
+        }
     }
+
+    Ok(warp::reply::json(&user))
 }
 
-async fn handle_user_data_update(requset_data: UserDataUpdate) -> Result<impl Reply, Rejection> {
-    let session_data = match read_session_data(&requset_data.username){
-        Ok(session_data) => session_data,
-        Err(_) => return Err(reject::custom(CustomRejection("Can not read authentication key".to_string()))),
+async fn handle_confirm_email_change(auth: AuthContext, requset_data: EmailChangeConfirm, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data: FullUserData = state.store.read_user_data(&auth.username)?;
+    let mut pending = match user_data.pending_email_change.take() {
+        Some(pending) => pending,
+        None => return Err(ApiError::NotFound("pending email change").into()),
+    };
+
+    let expired = match parse_timestamp(&pending.date) {
+        Some(date) => Utc::now() - date >= state.config.auth.email_change_code_lifetime(),
+        None => true,
     };
+    if expired {
+        state.store.write_user_data(user_data)?;
+        return Err(ApiError::InvalidCode.into());
+    }
 
-    if session_data.session_key != requset_data.session_key {
-        return Err(reject::custom(CustomRejection("Incorrect authentication key".to_string())))
-    } else {
-        let user_data: FullUserData = match read_user_data(&requset_data.username){
-            Ok(user_data) => user_data,
-            Err(_) => return Err(reject::custom(CustomRejection("Unable to read to user data".to_string()))),
-        };
+    if !code_matches(&state.tokens, &requset_data.code, &pending.code_hash) {
+        // Too many wrong guesses drop the change, it has to be asked for again
+        if count_wrong_guess(&mut pending.attempts, state.config.auth.otp_max_attempts) {
+            user_data.pending_email_change = Some(pending);
+        }
+        state.store.write_user_data(user_data)?;
+        return Err(ApiError::InvalidCode.into());
+    }
 
-        let new_user = match requset_data.new_username{
-            Some(new_user) => new_user,
-            None => user_data.username,
-        };
+    state.store.change_email(&auth.username, &pending.email)?;
 
-        let email = match requset_data.email{
-            Some(email) => Some(email),
-            None => user_data.email,
-        };
+    let mut changed_user_data = state.store.read_user_data(&auth.username)?;
+    changed_user_data.updated_at = Some(timestamp(Utc::now()));
+    state.store.write_user_data(changed_user_data)?;
 
-        let avatar = match requset_data.avatar{
-            Some(avatar) => Some(avatar),
-            None => user_data.avatar,
-        };
+    let user = UserData {
+        username: user_data.username,
+        email: Some(pending.email),
+        email_verified: true,
+        avatar: user_data.avatar,
+        pending_email: None,
+        two_factor_enabled: user_data.two_factor.is_some_and(|two_factor| two_factor.enabled),
+    };
 
-        let user = UserData {
-            username: new_user,
-            email: email,
-            avatar: avatar,
-        };
+    Ok(warp::reply::json(&user))
+}
+
+async fn handle_account_activity(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data: FullUserData = state.store.read_user_data(&auth.username)?;
+    Ok(warp::reply::json(&AccountActivity::from(&user_data)))
+}
 
-        return Ok(warp::reply::json(&user))
+async fn handle_list_sessions(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let sessions: Vec<SessionInfo> = state.store.read_sessions(&auth.username)?.into_iter().map(|session| SessionInfo {
+        current: auth.session.as_ref().is_some_and(|current| current.id == session.id),
+        id: session.id,
+        created_at: session.created_at,
+        last_seen_at: session.last_seen_at,
+        expires_at: session.expires_at,
+        device: session.device,
+        ip: session.ip,
+    }).collect();
+
+    Ok(warp::reply::json(&sessions))
+}
+
+async fn handle_revoke_session(auth: AuthContext, requset_data: RevokeSessionRequest, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    match state.store.delete_session(&auth.username, &requset_data.session_id) {
+        Ok(_) => {}
+        Err(StoreError::NotFound) => return Err(ApiError::NotFound("session").into()),
+        Err(err) => return Err(err.into()),
     }
+    state.store.revoke_refresh_tokens(&auth.username, Some(&requset_data.session_id))?;
+    Ok(warp::reply::json(&"Session revoked"))
 }
 
-async fn request_password_reset(req: RequestPassword) -> Result<impl Reply, Rejection> {
-    let username = match email_lookup(&req.email){
+async fn handle_revoke_all_sessions(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    state.store.delete_sessions_for_user(&auth.username)?;
+    state.store.revoke_refresh_tokens(&auth.username, None)?;
+    Ok(warp::reply::json(&"All sessions revoked"))
+}
+
+async fn request_password_reset(req: RequestPassword, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = account_guid(&state, &req.email);
+    let result = send_reset_request(req, state.clone()).await;
+    state.audit.record(AuditEvent::PasswordResetRequest, guid, &client, &result, None);
+    result
+}
+
+async fn send_reset_request(req: RequestPassword, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let username = match email_lookup(state.store.as_ref(), &req.email){
         Ok(username) => username,
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to find email".to_string()))),
+        Err(StoreError::NotFound) => return Err(ApiError::NotFound("email").into()),
+        Err(err) => return Err(err.into()),
     };
 
-    let otp_string: String = (0..4)
-        .map(|_| rand::thread_rng().gen_range(0..=9).to_string())
-        .collect();
+    send_password_reset_code(&state, &username, &req.email).await?;
+    Ok(warp::reply::json(&"OTP Sent to email address"))
+}
+
+// Emails a new reset code for /check_otp, replacing any earlier code and its attempt count
+async fn send_password_reset_code(state: &AppState, username: &str, email: &str) -> Result<(), Rejection> {
+    let auth_config = &state.config.auth;
+    let otp_string = random_code(auth_config.otp_length, &auth_config.otp_alphabet);
 
     let otp_data = OTPData {
-        otp: otp_string.clone(),
-        date: Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        otp_hash: state.tokens.hash(&otp_string),
+        date: timestamp(Utc::now()),
+        attempts: 0,
+    };
+
+    state.store.write_otp_data(otp_data, username)?;
+
+    if send_otp(state.mailer.as_ref(), &otp_string, username, email).await.is_err() {
+        return Err(ApiError::EmailDelivery.into());
+    }
+
+    Ok(())
+}
+
+async fn check_otp(req: OTPSubmit, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = account_guid(&state, &req.email);
+    let result = reset_password(req, state.clone()).await;
+    state.audit.record(AuditEvent::PasswordReset, guid, &client, &result, None);
+    result
+}
+
+async fn reset_password(req: OTPSubmit, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    // Unknown emails and missing codes get the same answer as a wrong code
+    let otp_data = email_lookup(state.store.as_ref(), &req.email)
+        .and_then(|username| state.store.read_otp_data(&username).map(|otp_data| (username, otp_data)));
+    let (username, mut otp_data) = match otp_data {
+        Ok(otp_data) => otp_data,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidCode.into()),
+        Err(err) => return Err(err.into()),
     };
 
-    match write_otp_data(otp_data, &username){
-        Ok(_) => {},
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to write otp data".to_string()))),
+    let expired = match parse_timestamp(&otp_data.date) {
+        Some(date) => Utc::now() - date >= state.config.auth.otp_lifetime(),
+        None => true,
     };
+    if expired {
+        state.store.delete_otp_data(&username)?;
+        return Err(ApiError::InvalidCode.into());
+    }
 
-    match send_otp(&otp_string, &username, &req.email).await{
-        Ok(_) => {},
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to send otp data".to_string()))),
+    if !code_matches(&state.tokens, &req.otp, &otp_data.otp_hash) {
+        // Too many wrong guesses use the code up
+        if count_wrong_guess(&mut otp_data.attempts, state.config.auth.otp_max_attempts) {
+            state.store.write_otp_data(otp_data, &username)?;
+        } else {
+            state.store.delete_otp_data(&username)?;
+        }
+        return Err(ApiError::InvalidCode.into());
+    }
+
+    // Single use, whatever happens next
+    state.store.delete_otp_data(&username)?;
+
+    let mut user_data: FullUserData = state.store.read_user_data(&username)?;
+    user_data.password = match state.passwords.hash(&req.password).await {
+        Ok(password_hash) => password_hash,
+        Err(err) => return Err(ApiError::Internal(err).into()),
     };
+    let now = timestamp(Utc::now());
+    user_data.password_changed_at = Some(now.clone());
+    user_data.updated_at = Some(now);
+    // Proving control of the email address is enough to lift a lockout
+    user_data.failed_login_count = 0;
+    user_data.locked_until = None;
+
+    state.store.write_user_data(user_data)?;
 
-    return Ok(warp::reply::json(&format!("OTP Sent to email address")));
+    // Whoever knew the old password is signed out everywhere
+    state.store.delete_sessions_for_user(&username)?;
+    state.store.revoke_refresh_tokens(&username, None)?;
+    Ok(warp::reply::json(&"OTP match and valid"))
 }
 
-async fn check_otp(req: OTPSubmit) -> Result<impl Reply, Rejection> {
-    let username = match email_lookup(&req.email){
+async fn request_magic_link(req: MagicLinkRequest, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let username = match email_lookup(state.store.as_ref(), &req.email){
         Ok(username) => username,
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to find email".to_string()))),
+        Err(StoreError::NotFound) => return Err(ApiError::NotFound("email").into()),
+        Err(err) => return Err(err.into()),
     };
 
-    let otp_data: OTPData = match read_otp_data(&username){
-        Ok(otp_data) => otp_data,
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to read otp data".to_string()))),
+    // Replacing the id voids any link sent before
+    let mut user_data: FullUserData = state.store.read_user_data(&username)?;
+    let link_id = random_string(32);
+    user_data.magic_link_id = Some(link_id.clone());
+    let email = user_data.email.clone().unwrap_or_default();
+    let display_name = user_data.username.clone();
+    state.store.write_user_data(user_data)?;
+
+    let binding = if req.bind_device { Some(random_string(32)) } else { None };
+    let claims = MagicLinkClaims {
+        username,
+        email: email.to_lowercase(),
+        link_id,
+        device: req.device,
+        binding_hash: binding.as_deref().map(|binding| state.tokens.hash(binding)),
     };
+    let token = state.tokens.sign("magic_link", &claims, state.config.auth.magic_link_lifetime());
 
-    let input_datetime = match DateTime::parse_from_str(&otp_data.date, "%Y-%m-%d %H:%M:%S"){
-        Ok(input_datetime) => input_datetime,
-        Err(_) => return Err(warp::reject::custom(CustomRejection("Failed to read otp date".to_string()))),
+    let base_url = match &state.config.auth.magic_link_url {
+        Some(magic_link_url) => magic_link_url.clone(),
+        None => format!("{}/magic_link/login", state.config.server.public_url.trim_end_matches('/')),
     };
+    let link = format!("{}{}token={}", base_url, if base_url.contains('?') { "&" } else { "?" }, token);
+
+    if send_magic_link(state.mailer.as_ref(), &link, &display_name, &email).await.is_err() {
+        return Err(ApiError::EmailDelivery.into());
+    }
+
+    Ok(warp::reply::json(&MagicLinkSent { message: "Login link sent to email address".to_string(), binding }))
+}
 
-    let current_datetime = Local::now();
-    let duration = match Duration::try_hours(2) {
-        Some(duration) => duration,
-        None => return Err(warp::reject::custom(CustomRejection("Failed to read otp date".to_string()))),
+async fn handle_magic_link_login(req: MagicLinkLogin, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = state.tokens.verify::<MagicLinkClaims>("magic_link", &req.token)
+        .and_then(|claims| account_guid(&state, &claims.username));
+    let result = magic_link_login(req, &client, state.clone()).await;
+    if result.is_err() {
+        state.audit.record(AuditEvent::Login, guid, &client, &result, None);
+    }
+    result
+}
+
+async fn magic_link_login(req: MagicLinkLogin, client: &ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let claims: MagicLinkClaims = match state.tokens.verify("magic_link", &req.token){
+        Some(claims) => claims,
+        None => return Err(ApiError::InvalidToken.into()),
+    };
+
+    let mut user_data: FullUserData = match state.store.read_user_data(&claims.username){
+        Ok(user_data) => user_data,
+        Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
     };
 
-    if current_datetime.signed_duration_since(input_datetime) < duration && &otp_data.otp == &req.otp {
-        let mut user_data: FullUserData = match read_user_data(&username){
-            Ok(user_data) => user_data,
-            Err(_) => return Err(reject::custom(CustomRejection("Unable to read to user data".to_string()))),
-        };
+    // Only the latest link counts, and only while the address it went to is still the account's
+    let current_email = user_data.email.clone().unwrap_or_default().to_lowercase();
+    if user_data.magic_link_id.as_deref() != Some(claims.link_id.as_str()) || current_email != claims.email {
+        return Err(ApiError::InvalidToken.into());
+    }
+
+    if let Some(binding_hash) = &claims.binding_hash {
+        let binding = req.binding.as_deref().unwrap_or_default();
+        if !constant_time_eq(&state.tokens.hash(binding), binding_hash) {
+            return Err(ApiError::InvalidToken.into());
+        }
+    }
 
-        let password_hash = hex_digest(Algorithm::SHA256, req.password.as_bytes());
-        user_data.password = password_hash;
+    // Following the link proves the address is theirs
+    user_data.magic_link_id = None;
+    user_data.email_verified = true;
+    let username = user_data.username.clone();
+    let guid = user_data.guid;
+    let methods = two_factor_methods(&state, &user_data);
+    state.store.write_user_data(user_data)?;
 
-        match write_user_data(user_data){
-            Ok(_) => return Ok(warp::reply::json(&format!("OTP match and valid"))),
-            Err(_) => return Err(warp::reject::custom(CustomRejection("Internal Error01".to_string()))),
-        };        
-    } else {
-        return Ok(warp::reply::json(&format!("OTP invalid or expired")));
+    // The link stands in for the password, not for a second factor
+    if !methods.is_empty() {
+        let challenge = two_factor_challenge(&state, username, guid, methods, claims.device, client, "magic_link")?;
+        return Ok(warp::reply::json(&challenge));
     }
+
+    let response = start_session(&state, &username, guid, claims.device, client, "magic_link")?;
+    Ok(warp::reply::json(&response))
+}
+
+// Opening the emailed link only shows this page; the token is spent by the POST its
+// button sends, so mail scanners that follow links don't use it up
+async fn handle_magic_link_page(req: MagicLinkLogin) -> Result<impl Reply, Rejection> {
+    let page = format!(
+        "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width\"><title>Log in</title></head>
+<body>
+<form method=\"post\" action=\"login\">
+<input type=\"hidden\" name=\"token\" value=\"{}\">
+<button type=\"submit\">Log in</button>
+</form>
+</body>
+</html>
+",
+        html_escape(&req.token)
+    );
+    Ok(warp::reply::with_header(warp::reply::html(page), "referrer-policy", "no-referrer"))
 }
 
-async fn add_routes(){
-    let get_health = warp::get()
-    .and(warp::path("health"))
+async fn add_routes(state: Arc<AppState>){
+    let get_health = warp::path("health")
+    .and(warp::get())
     .and_then(handle_get_health);
 
-    let register_user = warp::post()
-        .and(warp::path("register"))
-        .and(warp::body::json())
+    let register_user = warp::path("register")
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "register"))
+        .and(json_limited_by_account(state.clone(), "register"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
         .and_then(handle_register);
     
-    let login = warp::post()
+    let login = warp::path("login")
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "login"))
+        .and(json_limited_by_account(state.clone(), "login"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_login);
+
+    let me = warp::path("me")
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_get_me);
+
+    let reset_request = warp::path("reset_request")
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "reset_request"))
+        .and(json_limited_by_account(state.clone(), "reset_request"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(request_password_reset);
+
+    let magic_link = warp::path("magic_link")
+        .and(warp::path("request"))
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "magic_link"))
+        .and(json_limited_by_account(state.clone(), "magic_link"))
+        .and(with_state(state.clone()))
+        .and_then(request_magic_link);
+
+    // Json from apps, or the form on the page the emailed link opens
+    let magic_link_login = warp::path("magic_link")
+        .and(warp::path("login"))
+        .and(warp::post())
+        .and(warp::body::json().or(warp::body::form()).unify())
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_magic_link_login);
+
+    // Target of the emailed link when no magic_link_url page is configured
+    let magic_link_login_link = warp::path("magic_link")
         .and(warp::path("login"))
+        .and(warp::get())
+        .and(warp::query::<MagicLinkLogin>())
+        .and_then(handle_magic_link_page);
+
+    let otp_check = warp::path("check_otp")
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "check_otp"))
+        .and(json_limited_by_account(state.clone(), "check_otp"))
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(check_otp);
+
+    let update_user_data = warp::path("update_user_data")
+        .and(warp::post())
+        .and(with_auth(state.clone()))
         .and(warp::body::json())
-        .and_then(handle_login);
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_user_data_update);
 
-    let retrieve_user_data = warp::post()
-        .and(warp::path("user_data"))
+    let confirm_email_change = warp::path("confirm_email_change")
+        .and(warp::post())
+        .and(with_auth(state.clone()))
         .and(warp::body::json())
-        .and_then(handle_user_data_retrieval);
+        .and(with_state(state.clone()))
+        .and_then(handle_confirm_email_change);
 
-    let reset_request = warp::post()
-        .and(warp::path("reset_request"))
+    let verify_email = warp::path("verify_email")
+        .and(warp::post())
         .and(warp::body::json())
-        .and_then(request_password_reset);
+        .and(with_state(state.clone()))
+        .and_then(handle_verify_email);
 
-    let otp_check = warp::post()
-        .and(warp::path("check_otp"))
+    // Links in verification emails arrive as a GET with the token in the query string
+    let verify_email_link = warp::path("verify_email")
+        .and(warp::get())
+        .and(warp::query::<VerifyEmail>())
+        .and(with_state(state.clone()))
+        .and_then(handle_verify_email);
+
+    let resend_verification = warp::path("resend_verification")
+        .and(warp::post())
         .and(warp::body::json())
-        .and_then(check_otp);
+        .and(with_state(state.clone()))
+        .and_then(handle_resend_verification);
+
+    let account_activity = warp::path("account")
+        .and(warp::path("activity"))
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_account_activity);
 
-    let update_user_data = warp::post()
-        .and(warp::path("update_user_data"))
+    let list_sessions = warp::path("sessions")
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_list_sessions);
+
+    let revoke_session = warp::path("revoke_session")
+        .and(warp::post())
+        .and(with_auth(state.clone()))
         .and(warp::body::json())
-        .and_then(handle_user_data_update);
+        .and(with_state(state.clone()))
+        .and_then(handle_revoke_session);
+
+    let revoke_all_sessions = warp::path("revoke_all_sessions")
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_revoke_all_sessions);
+
+    let refresh_token = warp::path("token")
+        .and(warp::path("refresh"))
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_refresh_token);
+
+    // Public key for checking access tokens offline
+    let jwks = warp::path(".well-known")
+        .and(warp::path("jwks.json"))
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(handle_jwks);
 
-    // Combine filters and run the server
+    // Combine filters and run the server. Each route matches its path before its
+    // method, so unknown paths are a 404 rather than a 405.
     let routes = register_user
         .or(login)
-        .or(retrieve_user_data)
+        .or(me)
         .or(update_user_data)
+        .or(confirm_email_change)
+        .or(verify_email)
+        .or(verify_email_link)
+        .or(resend_verification)
         .or(reset_request)
         .or(otp_check)
+        .or(magic_link)
+        .or(magic_link_login)
+        .or(magic_link_login_link)
+        .or(account_activity)
+        .or(list_sessions)
+        .or(revoke_session)
+        .or(revoke_all_sessions)
+        .or(refresh_token)
+        .or(jwks)
         .or(get_health)
-        .recover(handle_custom_rejection);
+        .or(oauth::routes(state.clone()))
+        .or(two_factor::routes(state.clone()))
+        .or(passkeys::routes(state.clone()))
+        .or(admin::routes(state.clone()))
+        .or(deletion::routes(state.clone()))
+        .or(export::routes(state.clone()))
+        .or(audit::routes(state.clone()))
+        .recover(handle_rejection);
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    // The address was checked when the config was loaded
+    let address: IpAddr = state.config.server.bind_address.parse().unwrap();
+    warp::serve(routes).run((address, state.config.server.port)).await;
 }
\ No newline at end of file
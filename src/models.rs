@@ -1,11 +1,6 @@
 use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct ProductRequest {
-    pub username: String,
-    pub shared_key: String,
-}
+use webauthn_rs::prelude::{Passkey, PublicKeyCredential, RegisterPublicKeyCredential};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RequestPassword {
@@ -21,8 +16,14 @@ pub struct OTPSubmit {
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct OTPData {
-    pub otp: String,
+    /// Keyed hash of the code. Files from before hashing hold the plain code
+    /// under `otp`, which then never matches and has to be requested again.
+    #[serde(alias = "otp")]
+    pub otp_hash: String,
     pub date: String,
+    /// Wrong guesses so far
+    #[serde(default)]
+    pub attempts: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -30,23 +31,155 @@ pub struct LoginRequest {
     pub username: String,
     pub password: String,
     pub version: f32,
+    pub device: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LoginResponse {
     pub session_key: String,
-    pub username: String
+    pub username: String,
+    // Only present when access tokens are enabled
+    #[serde(flatten)]
+    pub tokens: Option<TokenResponse>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct UserDataRequest {
-    pub session_key: String,
-    pub username: String,
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+/// Claims of a signed access token. `sub` is the account guid, which unlike the
+/// username never changes.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AccessClaims {
+    pub iss: String,
+    pub sub: String,
+    pub username: String,
+    pub scope: String,
+    /// Client id for tokens issued to an OAuth client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aud: Option<String>,
+    /// Only in tokens from a login, never in ones issued to OAuth clients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub permissions: Vec<String>,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/// A refresh token as kept server side; only the hash of the token is stored.
+/// Each rotation adds a token to the same family and marks the old one used.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RefreshTokenData {
+    pub token_hash: String,
+    pub family_id: String,
+    pub username: String,
+    pub created_at: String,
+    pub expires_at: String,
+    pub used: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SessionData {
+    pub id: String,
     pub session_key: String,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub current: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccountActivity {
+    pub created_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub last_login_at: Option<String>,
+    pub last_login_ip: Option<String>,
+    pub failed_login_count: u32,
+    pub locked_until: Option<String>,
+    pub password_changed_at: Option<String>,
+    pub deletion_scheduled_at: Option<String>,
+}
+
+impl From<&FullUserData> for AccountActivity {
+    fn from(user_data: &FullUserData) -> Self {
+        AccountActivity {
+            created_at: user_data.created_at.clone(),
+            updated_at: user_data.updated_at.clone(),
+            last_login_at: user_data.last_login_at.clone(),
+            last_login_ip: user_data.last_login_ip.clone(),
+            failed_login_count: user_data.failed_login_count,
+            locked_until: user_data.locked_until.clone(),
+            password_changed_at: user_data.password_changed_at.clone(),
+            deletion_scheduled_at: user_data.deletion_scheduled_at.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportQuery {
+    /// json (the default) or zip
+    pub format: Option<String>,
+}
+
+/// Everything held about an account, leaving out secrets: the password hash, reset and
+/// confirmation codes, session keys, tokens and two-factor or passkey key material
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DataExport {
+    pub exported_at: String,
+    pub profile: ExportProfile,
+    pub activity: AccountActivity,
+    pub sessions: Vec<SessionInfo>,
+    pub email_events: EmailEvents,
+    /// Registration, logins, password resets and profile changes from the audit log
+    pub audit_log: Vec<AuditEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ExportProfile {
+    pub username: String,
+    pub guid: String,
+    pub email: Option<String>,
+    pub avatar: Option<String>,
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+    pub two_factor_enabled: bool,
+    pub passkeys: Vec<PasskeyInfo>,
+    pub oauth_consents: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailEvents {
+    pub email_verified: bool,
+    pub verification_sent_at: Option<String>,
+    pub pending_email: Option<String>,
+    pub pending_email_requested_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -60,7 +193,10 @@ pub struct RegisterUser {
 pub struct UserData {
     pub username: String,
     pub email: Option<String>,
+    pub email_verified: bool,
     pub avatar: Option<String>,
+    pub pending_email: Option<String>,
+    pub two_factor_enabled: bool,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -70,15 +206,125 @@ pub struct FullUserData {
     pub email: Option<String>,
     pub avatar: Option<String>,
     pub password: String,
+    #[serde(default)]
+    pub pending_email_change: Option<PendingEmailChange>,
+    #[serde(default)]
+    pub email_verified: bool,
+    #[serde(default)]
+    pub verification_sent_at: Option<String>,
+    /// OAuth client id -> space separated scopes the user has agreed to share with it
+    #[serde(default)]
+    pub oauth_consents: HashMap<String, String>,
+    #[serde(default)]
+    pub two_factor: Option<TwoFactorData>,
+    #[serde(default)]
+    pub passkeys: Vec<PasskeyData>,
+    /// Id of the latest login link sent; only that link works, and only once
+    #[serde(default)]
+    pub magic_link_id: Option<String>,
+    /// Wrong passwords in a row since the last successful login
+    #[serde(default)]
+    pub failed_login_count: u32,
+    /// Logins are refused until then, after too many wrong passwords
+    #[serde(default)]
+    pub locked_until: Option<String>,
+    /// Set by an admin; no login succeeds until they unlock the account
+    #[serde(default)]
+    pub disabled: bool,
+    /// Roles on top of "user", which every account has
+    #[serde(default)]
+    pub roles: Vec<String>,
+    /// Granted directly rather than through a role
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    // Unknown for accounts created before they were tracked
+    #[serde(default)]
+    pub created_at: Option<String>,
+    /// When the username, email, avatar or password last changed
+    #[serde(default)]
+    pub updated_at: Option<String>,
+    #[serde(default)]
+    pub last_login_at: Option<String>,
+    #[serde(default)]
+    pub last_login_ip: Option<String>,
+    #[serde(default)]
+    pub password_changed_at: Option<String>,
+    /// The account is erased after this unless the user logs in first
+    #[serde(default)]
+    pub deletion_scheduled_at: Option<String>,
+    /// The second factor challenge from the latest login, until a session or too many wrong codes spend it
+    #[serde(default)]
+    pub login_challenge: Option<LoginChallenge>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoginChallenge {
+    pub id: String,
+    pub failed_attempts: u32,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TwoFactorData {
+    /// Base32 TOTP secret
+    pub secret: String,
+    /// False until the first code from the authenticator app has been confirmed
+    pub enabled: bool,
+    /// Hashes of the unused recovery codes
+    pub recovery_codes: Vec<String>,
+    /// Time step of the last accepted code, so a code can't be used twice
+    pub last_used_step: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PasskeyData {
+    /// Base64url credential id, as the browser reports it
+    pub id: String,
+    pub name: String,
+    /// Public key and signature counter
+    pub passkey: Passkey,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PendingEmailChange {
+    pub email: String,
+    /// Keyed hash of the code. Changes requested before hashing hold the plain code
+    /// under `code`, which then never matches and has to be requested again.
+    #[serde(alias = "code")]
+    pub code_hash: String,
+    pub date: String,
+    /// Wrong guesses so far
+    #[serde(default)]
+    pub attempts: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-pub struct UserDataUpdate {
+pub struct EmailVerificationClaims {
     pub username: String,
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResendVerification {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EmailChangeConfirm {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserDataUpdate {
     pub new_username: Option<String>,
     pub email: Option<String>,
     pub avatar: Option<String>,
-    pub session_key: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -99,12 +345,383 @@ pub struct EmailAddress {
     pub email: String,
 }
 
-#[derive(Debug)]
-pub struct CustomIoError(pub std::io::Error);
+/// An application allowed to sign users in through the OAuth endpoints. Public
+/// clients (apps that can't keep a secret) have no secret and rely on PKCE alone.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct OAuthClient {
+    pub client_id: String,
+    pub name: String,
+    pub secret_hash: Option<String>,
+    pub redirect_uris: Vec<String>,
+    /// Lowercased username of the account that registered the client
+    pub owner: String,
+    pub created_at: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegisterClient {
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    #[serde(default)]
+    pub public: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegisteredClient {
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+}
+
+/// A pending authorization code, stored by hash until it is exchanged or expires.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthCodeData {
+    pub code_hash: String,
+    pub client_id: String,
+    pub username: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub code_challenge: String,
+    pub nonce: Option<String>,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AuthorizeRequest {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub state: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthorizeDecision {
+    #[serde(flatten)]
+    pub request: AuthorizeRequest,
+    pub approve: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ConsentPrompt {
+    pub client_id: String,
+    pub client_name: String,
+    pub scopes: Vec<String>,
+    /// True when the user already agreed to these scopes and the prompt can be skipped
+    pub consented: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthorizeRedirect {
+    pub redirect_to: String,
+}
+
+/// Form body of the token endpoint
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OAuthTokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: i64,
+    pub scope: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    #[serde(flatten)]
+    pub user: UserInfo,
+}
+
+/// Standard OpenID claims about the user, limited to the granted scopes. `sub` is the account guid.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub picture: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    /// otpauth:// URI to show as a QR code
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+/// Deleting an account needs the password again
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+}
+
+/// Turning two-factor off or replacing recovery codes needs the password and a current code
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorReauth {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by login instead of a session when the account has two-factor enabled
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorChallenge {
+    pub two_factor_required: bool,
+    /// "totp" and/or "passkey"
+    pub methods: Vec<String>,
+    pub challenge_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorChallengeClaims {
+    pub username: String,
+    pub guid: u128,
+    /// Matches the account's login_challenge while the challenge can still be used
+    pub challenge_id: String,
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TwoFactorLogin {
+    pub challenge_token: String,
+    /// A code from the authenticator app or an unused recovery code
+    pub code: String,
+}
+
+/// Options for navigator.credentials.create() or .get(), with the id to send back alongside the result
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyCeremony<T> {
+    pub ceremony_id: String,
+    pub options: T,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyRegistrationFinish {
+    pub ceremony_id: String,
+    pub name: Option<String>,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyLoginStart {
+    /// From a password login that asked for a second factor; without it any passkey
+    /// stored on the authenticator can sign in
+    pub challenge_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasskeyLoginFinish {
+    pub ceremony_id: String,
+    pub credential: PublicKeyCredential,
+    pub device: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RemovePasskey {
+    pub id: String,
+    #[serde(flatten)]
+    pub reauth: PasskeyReauth,
+}
+
+/// The current password or a two-factor code, either one will do
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PasskeyReauth {
+    pub password: Option<String>,
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+    pub device: Option<String>,
+    /// Only accept the link together with a secret returned to this request, so it
+    /// has to be finished where it was asked for
+    #[serde(default)]
+    pub bind_device: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkSent {
+    pub message: String,
+    /// Present when bind_device was set; send it back along with the token
+    pub binding: Option<String>,
+}
 
-impl warp::reject::Reject for CustomIoError {}
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkClaims {
+    pub username: String,
+    pub email: String,
+    pub link_id: String,
+    pub device: Option<String>,
+    pub binding_hash: Option<String>,
+}
 
-#[derive(Debug)]
-pub struct CustomRejection(pub String);
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MagicLinkLogin {
+    pub token: String,
+    pub binding: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminUserQuery {
+    pub search: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminUserSummary {
+    pub username: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub disabled: bool,
+    pub locked_until: Option<String>,
+    pub created_at: Option<String>,
+    pub last_login_at: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminUserPage {
+    pub users: Vec<AdminUserSummary>,
+    pub page: usize,
+    pub per_page: usize,
+    pub total: usize,
+}
 
-impl warp::reject::Reject for CustomRejection {}
\ No newline at end of file
+/// Everything kept about an account except its password hash and other secrets
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminUserView {
+    pub username: String,
+    pub guid: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+    pub avatar: Option<String>,
+    pub pending_email: Option<String>,
+    pub two_factor_enabled: bool,
+    pub passkeys: Vec<PasskeyInfo>,
+    pub oauth_consents: HashMap<String, String>,
+    pub disabled: bool,
+    pub roles: Vec<String>,
+    /// Everything the roles and direct grants add up to
+    pub permissions: Vec<String>,
+    #[serde(flatten)]
+    pub activity: AccountActivity,
+    pub sessions: Vec<SessionInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminLock {
+    /// Locks the account for this long, or until it is unlocked when left out
+    pub minutes: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminEmailChange {
+    pub email: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RoleChange {
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEvent {
+    Register,
+    Login,
+    PasswordResetRequest,
+    PasswordReset,
+    ProfileUpdate,
+    /// A password or magic link login that still needs a second factor
+    SecondFactorRequired,
+    AdminDeleteAccount,
+    AdminPasswordReset,
+    AdminRevokeSessions,
+    AdminLock,
+    AdminUnlock,
+    AdminEmailChange,
+    AdminRoleChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    Failure,
+}
+
+/// One line of the audit log
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub event: AuditEvent,
+    /// None when the request didn't name an existing account, or an admin action was
+    /// taken with the admin API key
+    pub guid: Option<String>,
+    /// The account an admin action was taken on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub outcome: AuditOutcome,
+    /// The error code of a failure, the method a login used, or what a profile update or admin action changed
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct AuditQuery {
+    /// Admins only, the account whose entries to list
+    pub username: Option<String>,
+    pub event: Option<AuditEvent>,
+    pub outcome: Option<AuditOutcome>,
+    /// Only entries from before this time, to page back through older ones
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
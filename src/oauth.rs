@@ -0,0 +1,385 @@
+use std::sync::Arc;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use chrono::Utc;
+use crypto_hash::{digest, Algorithm as HashAlgorithm};
+use rand::Rng;
+use reqwest::Url;
+use serde_json::json;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::auth::{bearer_token, check_account_usable, with_auth, with_optional_auth, AuthContext};
+use crate::roles::require_permission;
+use crate::errors::{ApiError, StoreError};
+use crate::utils::{hash_token, parse_timestamp, random_string, timestamp};
+use crate::*;
+
+/// Scopes a client can ask for. `openid` adds an ID token, `profile` and `email`
+/// add the matching claims to it and to userinfo.
+const SUPPORTED_SCOPES: &[&str] = &["openid", "profile", "email"];
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let discovery = warp::path(".well-known")
+        .and(warp::path("openid-configuration"))
+        .and(warp::get())
+        .and(with_state(state.clone()))
+        .and_then(handle_discovery);
+
+    let register_client = warp::path("oauth")
+        .and(warp::path("clients"))
+        .and(warp::post())
+        .and(require_permission(state.clone(), "oauth_clients:create"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_register_client);
+
+    let authorize = warp::path("oauth")
+        .and(warp::path("authorize"))
+        .and(warp::get())
+        .and(with_optional_auth(state.clone()))
+        .and(warp::query::<AuthorizeRequest>())
+        .and(warp::query::raw())
+        .and(with_state(state.clone()))
+        .and_then(handle_authorize);
+
+    let authorize_decision = warp::path("oauth")
+        .and(warp::path("authorize"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_authorize_decision);
+
+    let token = warp::path("oauth")
+        .and(warp::path("token"))
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::form())
+        .and(with_state(state.clone()))
+        .and_then(handle_token);
+
+    let userinfo = warp::path("oauth")
+        .and(warp::path("userinfo"))
+        .and(warp::get().or(warp::post()).unify())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_state(state.clone()))
+        .and_then(handle_userinfo);
+
+    discovery
+        .or(register_client)
+        .or(authorize)
+        .or(authorize_decision)
+        .or(token)
+        .or(userinfo)
+}
+
+fn issuer(state: &AppState) -> String {
+    state.config.server.public_url.trim_end_matches('/').to_string()
+}
+
+async fn handle_discovery(state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let issuer = issuer(&state);
+    Ok(warp::reply::json(&json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "scopes_supported": SUPPORTED_SCOPES,
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": ["EdDSA"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "nonce", "preferred_username", "picture", "email", "email_verified"],
+    })))
+}
+
+// Redirect URIs are compared exactly, so they must be absolute and can't carry a fragment
+fn valid_redirect_uri(redirect_uri: &str) -> bool {
+    match Url::parse(redirect_uri) {
+        Ok(url) => url.fragment().is_none() && url.has_host() && (url.scheme() == "https" || url.scheme() == "http"),
+        Err(_) => false,
+    }
+}
+
+async fn handle_register_client(auth: AuthContext, req: RegisterClient, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    if req.name.trim().is_empty() {
+        return Err(ApiError::validation("name", "Client name can not be empty").into());
+    }
+
+    if req.redirect_uris.is_empty() || !req.redirect_uris.iter().all(|uri| valid_redirect_uri(uri)) {
+        return Err(ApiError::validation("redirect_uris", "Redirect URIs must be absolute http(s) URLs without a fragment").into());
+    }
+
+    // Public clients, like single page or mobile apps, can't keep a secret
+    let client_secret = if req.public { None } else { Some(random_string(48)) };
+    let client = OAuthClient {
+        client_id: Uuid::from_u128(rand::thread_rng().gen()).to_string(),
+        name: req.name,
+        secret_hash: client_secret.as_deref().map(hash_token),
+        redirect_uris: req.redirect_uris,
+        owner: auth.username,
+        created_at: timestamp(Utc::now()),
+    };
+
+    let registered = RegisteredClient {
+        client_id: client.client_id.clone(),
+        client_secret,
+        name: client.name.clone(),
+        redirect_uris: client.redirect_uris.clone(),
+    };
+    state.store.create_oauth_client(client)?;
+
+    Ok(warp::reply::with_status(warp::reply::json(&registered), StatusCode::CREATED))
+}
+
+// Checks an authorization request before anything is shown to the user. Problems
+// with the client or redirect URI are never sent to the redirect URI.
+fn check_authorize_request(state: &AppState, req: &AuthorizeRequest) -> Result<(OAuthClient, Vec<String>), Rejection> {
+    let client = match state.store.read_oauth_client(&req.client_id) {
+        Ok(client) => client,
+        Err(StoreError::NotFound) => return Err(ApiError::validation("client_id", "Unknown client").into()),
+        Err(err) => return Err(err.into()),
+    };
+
+    if !client.redirect_uris.contains(&req.redirect_uri) {
+        return Err(ApiError::validation("redirect_uri", "Redirect URI is not registered for this client").into());
+    }
+
+    if req.response_type != "code" {
+        return Err(ApiError::validation("response_type", "Only the code response type is supported").into());
+    }
+
+    let scopes: Vec<String> = req.scope.split_whitespace().map(str::to_string).collect();
+    if scopes.is_empty() || !scopes.iter().all(|scope| SUPPORTED_SCOPES.contains(&scope.as_str())) {
+        return Err(ApiError::validation("scope", "Unsupported scope").into());
+    }
+
+    // PKCE is required for every client, and only with SHA-256
+    if req.code_challenge.as_deref().unwrap_or_default().len() < 43 || req.code_challenge_method.as_deref() != Some("S256") {
+        return Err(ApiError::validation("code_challenge", "A S256 code challenge is required").into());
+    }
+
+    Ok((client, scopes))
+}
+
+fn has_consent(user_data: &FullUserData, client_id: &str, scopes: &[String]) -> bool {
+    match user_data.oauth_consents.get(client_id) {
+        Some(granted) => scopes.iter().all(|scope| granted.split(' ').any(|granted| granted == scope)),
+        None => false,
+    }
+}
+
+async fn handle_authorize(auth: Option<AuthContext>, req: AuthorizeRequest, query: String, state: Arc<AppState>) -> Result<warp::reply::Response, Rejection> {
+    let (client, scopes) = check_authorize_request(&state, &req)?;
+
+    // Browsers arrive here without a token; the login page signs the user in and comes back
+    let auth = match (auth, &state.config.oauth.login_url) {
+        (Some(auth), _) => auth,
+        (None, Some(login_url)) => {
+            let location = format!("{}{}{}", login_url, if login_url.contains('?') { "&" } else { "?" }, query);
+            return Ok(warp::reply::with_header(StatusCode::FOUND, "Location", location).into_response());
+        }
+        (None, None) => return Err(ApiError::MissingToken.into()),
+    };
+
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let prompt = ConsentPrompt {
+        consented: has_consent(&user_data, &client.client_id, &scopes),
+        client_id: client.client_id,
+        client_name: client.name,
+        scopes,
+    };
+
+    Ok(warp::reply::json(&prompt).into_response())
+}
+
+async fn handle_authorize_decision(auth: AuthContext, decision: AuthorizeDecision, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let req = decision.request;
+    let (client, scopes) = check_authorize_request(&state, &req)?;
+
+    let mut redirect_to = match Url::parse(&req.redirect_uri) {
+        Ok(redirect_to) => redirect_to,
+        Err(_) => return Err(ApiError::validation("redirect_uri", "Redirect URI is not registered for this client").into()),
+    };
+
+    if !decision.approve {
+        redirect_to.query_pairs_mut().append_pair("error", "access_denied");
+    } else {
+        let mut user_data = state.store.read_user_data(&auth.username)?;
+        if !has_consent(&user_data, &client.client_id, &scopes) {
+            let mut granted: Vec<String> = user_data.oauth_consents.get(&client.client_id).map(|granted| granted.split(' ').map(str::to_string).collect()).unwrap_or_default();
+            granted.extend(scopes.iter().cloned());
+            granted.sort();
+            granted.dedup();
+            user_data.oauth_consents.insert(client.client_id.clone(), granted.join(" "));
+            state.store.write_user_data(user_data)?;
+        }
+
+        let code = random_string(32);
+        state.store.create_auth_code(AuthCodeData {
+            code_hash: hash_token(&code),
+            client_id: client.client_id,
+            username: auth.username,
+            redirect_uri: req.redirect_uri.clone(),
+            scope: scopes.join(" "),
+            code_challenge: req.code_challenge.unwrap_or_default(),
+            nonce: req.nonce,
+            expires_at: timestamp(Utc::now() + state.config.oauth.authorization_code_lifetime()),
+        })?;
+        redirect_to.query_pairs_mut().append_pair("code", &code);
+    }
+
+    if let Some(client_state) = &req.state {
+        redirect_to.query_pairs_mut().append_pair("state", client_state);
+    }
+
+    Ok(warp::reply::json(&AuthorizeRedirect { redirect_to: redirect_to.to_string() }))
+}
+
+// The token endpoint answers in the RFC 6749 error format that OAuth client libraries expect
+fn oauth_error(status: StatusCode, error: &str, description: &str) -> warp::reply::Response {
+    let body = json!({ "error": error, "error_description": description });
+    warp::reply::with_status(warp::reply::json(&body), status).into_response()
+}
+
+// Client credentials come from HTTP Basic auth or the form body
+fn client_credentials(authorization: Option<&str>, req: &OAuthTokenRequest) -> Option<(String, Option<String>)> {
+    if let Some(credentials) = authorization.and_then(|header| header.strip_prefix("Basic ")) {
+        let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+        let (client_id, client_secret) = credentials.split_once(':')?;
+        return Some((client_id.to_string(), Some(client_secret.to_string())));
+    }
+
+    Some((req.client_id.clone()?, req.client_secret.clone()))
+}
+
+async fn handle_token(authorization: Option<String>, req: OAuthTokenRequest, state: Arc<AppState>) -> Result<warp::reply::Response, Rejection> {
+    if req.grant_type != "authorization_code" {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type", "Only authorization_code is supported"));
+    }
+
+    let (client_id, client_secret) = match client_credentials(authorization.as_deref(), &req) {
+        Some(credentials) => credentials,
+        None => return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed")),
+    };
+
+    let client = match state.store.read_oauth_client(&client_id) {
+        Ok(client) => client,
+        Err(StoreError::NotFound) => return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed")),
+        Err(err) => return Err(err.into()),
+    };
+
+    if let Some(secret_hash) = &client.secret_hash {
+        if client_secret.as_deref().map(hash_token).as_ref() != Some(secret_hash) {
+            return Ok(oauth_error(StatusCode::UNAUTHORIZED, "invalid_client", "Client authentication failed"));
+        }
+    }
+
+    let (code, code_verifier) = match (&req.code, &req.code_verifier) {
+        (Some(code), Some(code_verifier)) => (code, code_verifier),
+        _ => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_request", "code and code_verifier are required")),
+    };
+
+    // Taking the code deletes it, so a replayed code fails even if this exchange does too
+    let code = match state.store.take_auth_code(&hash_token(code)) {
+        Ok(code) => code,
+        Err(StoreError::NotFound) => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Authorization code invalid or expired")),
+        Err(err) => return Err(err.into()),
+    };
+
+    let expired = match parse_timestamp(&code.expires_at) {
+        Some(expires_at) => Utc::now() >= expires_at,
+        None => true,
+    };
+    let challenge = URL_SAFE_NO_PAD.encode(digest(HashAlgorithm::SHA256, code_verifier.as_bytes()));
+    if expired || code.client_id != client.client_id || req.redirect_uri.as_deref() != Some(code.redirect_uri.as_str()) || challenge != code.code_challenge {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Authorization code invalid or expired"));
+    }
+
+    let user_data = match state.store.read_user_data(&code.username) {
+        Ok(user_data) => user_data,
+        Err(StoreError::NotFound) => return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "Authorization code invalid or expired")),
+        Err(err) => return Err(err.into()),
+    };
+    // A code issued before the account was locked, disabled or scheduled for deletion
+    if check_account_usable(&user_data).is_err() {
+        return Ok(oauth_error(StatusCode::BAD_REQUEST, "invalid_grant", "The account can't sign in right now"));
+    }
+
+    let scopes: Vec<&str> = code.scope.split(' ').collect();
+    let (access_token, expires_in) = issue_access_token(&state, user_data.guid, &user_data.username, &code.scope, Some(client.client_id.clone()), None);
+
+    let mut id_token = None;
+    if scopes.contains(&"openid") {
+        let now = Utc::now();
+        let claims = IdTokenClaims {
+            iss: issuer(&state),
+            aud: client.client_id,
+            iat: now.timestamp(),
+            exp: now.timestamp() + expires_in,
+            nonce: code.nonce,
+            user: user_info(&user_data, &scopes),
+        };
+        id_token = Some(state.jwt.sign(&claims));
+    }
+
+    let response = OAuthTokenResponse {
+        access_token,
+        token_type: "Bearer".to_string(),
+        expires_in,
+        scope: code.scope.clone(),
+        id_token,
+    };
+    Ok(warp::reply::with_header(warp::reply::json(&response), "Cache-Control", "no-store").into_response())
+}
+
+// Same data as UserData, under the standard OpenID claim names
+fn user_info(user_data: &FullUserData, scopes: &[&str]) -> UserInfo {
+    let mut info = UserInfo {
+        sub: Uuid::from_u128(user_data.guid).to_string(),
+        preferred_username: None,
+        picture: None,
+        email: None,
+        email_verified: None,
+    };
+
+    if scopes.contains(&"profile") {
+        info.preferred_username = Some(user_data.username.clone());
+        info.picture = user_data.avatar.clone();
+    }
+
+    if scopes.contains(&"email") {
+        info.email = user_data.email.clone();
+        info.email_verified = Some(user_data.email_verified);
+    }
+
+    info
+}
+
+async fn handle_userinfo(authorization: Option<String>, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let claims: AccessClaims = match authorization.as_deref().and_then(bearer_token).and_then(|token| state.jwt.verify(token)) {
+        Some(claims) => claims,
+        None => return Err(ApiError::InvalidToken.into()),
+    };
+
+    let scopes: Vec<&str> = claims.scope.split(' ').collect();
+    if !scopes.contains(&"openid") {
+        return Err(ApiError::InvalidToken.into());
+    }
+
+    // The token names the user, but only counts while the guid still matches
+    let user_data = match state.store.read_user_data(&claims.username) {
+        Ok(user_data) if Uuid::from_u128(user_data.guid).to_string() == claims.sub => user_data,
+        Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
+    };
+    check_account_usable(&user_data)?;
+
+    Ok(warp::reply::json(&user_info(&user_data, &scopes)))
+}
@@ -0,0 +1,372 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use rand::Rng;
+use uuid::Uuid;
+use warp::{Filter, Rejection, Reply};
+use webauthn_rs::prelude::{
+    DiscoverableAuthentication, DiscoverableKey, PasskeyAuthentication, PasskeyRegistration, Url, Uuid as WebauthnUuid, Webauthn,
+    WebauthnBuilder,
+};
+
+use crate::audit::{client_info, ClientInfo};
+use crate::auth::{check_not_locked, with_auth, AuthContext};
+use crate::config::WebauthnConfig;
+use crate::errors::{ApiError, StoreError};
+use crate::password::PasswordCheck;
+use crate::rate_limit::{count_failed_login, limit_ip};
+use crate::two_factor::{check_code, CHALLENGE_PURPOSE};
+use crate::utils::timestamp;
+use crate::*;
+
+// Login options can be asked for without an account, so the ceremonies waiting to be
+// finished are capped
+const MAX_CEREMONIES: usize = 10_000;
+
+// State the server keeps between handing out options and checking the browser's answer
+enum Ceremony {
+    Registration { username: String, state: PasskeyRegistration },
+    /// After a password login, with the passkeys of one account
+    SecondFactor { username: String, guid: u128, device: Option<String>, state: PasskeyAuthentication },
+    /// Without a username, with whichever passkey the authenticator offers
+    Passwordless(DiscoverableAuthentication),
+}
+
+/// WebAuthn relying party plus the ceremonies in progress. Ceremonies only live in
+/// memory, a restart just means the user has to try again.
+pub struct Passkeys {
+    webauthn: Webauthn,
+    timeout: Duration,
+    ceremonies: Mutex<HashMap<String, (Instant, Ceremony)>>,
+}
+
+impl Passkeys {
+    /// None when no origin is configured. The origin was checked when the config was loaded.
+    pub fn new(config: &WebauthnConfig) -> Result<Option<Passkeys>, String> {
+        let origin = match &config.origin {
+            Some(origin) => origin,
+            None => return Ok(None),
+        };
+        let origin = Url::parse(origin).map_err(|err| err.to_string())?;
+        let rp_id = origin.domain().unwrap_or_default().to_string();
+
+        let timeout = Duration::from_secs(config.ceremony_timeout_seconds as u64);
+        let webauthn = WebauthnBuilder::new(&rp_id, &origin)
+            .and_then(|builder| builder.rp_name(&config.rp_name).timeout(timeout).build())
+            .map_err(|err| err.to_string())?;
+
+        Ok(Some(Passkeys { webauthn, timeout, ceremonies: Mutex::new(HashMap::new()) }))
+    }
+
+    fn begin(&self, ceremony: Ceremony) -> Result<String, Rejection> {
+        let now = Instant::now();
+        let mut ceremonies = self.ceremonies.lock().unwrap();
+        ceremonies.retain(|_, (expires_at, _)| *expires_at > now);
+        if ceremonies.len() >= MAX_CEREMONIES {
+            // Room opens up when the oldest ceremony expires
+            let retry_after = ceremonies.values().map(|(expires_at, _)| expires_at.duration_since(now)).min().unwrap_or(self.timeout);
+            return Err(ApiError::RateLimited(retry_after.as_secs() + 1).into());
+        }
+
+        let ceremony_id = Uuid::from_u128(rand::thread_rng().gen()).to_string();
+        ceremonies.insert(ceremony_id.clone(), (now + self.timeout, ceremony));
+        Ok(ceremony_id)
+    }
+
+    // Each ceremony can be finished once, whether it succeeds or not
+    fn take(&self, ceremony_id: &str) -> Option<Ceremony> {
+        match self.ceremonies.lock().unwrap().remove(ceremony_id) {
+            Some((expires_at, ceremony)) if expires_at > Instant::now() => Some(ceremony),
+            _ => None,
+        }
+    }
+}
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list = warp::path("passkeys")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_list_passkeys);
+
+    let register_options = warp::path("passkeys")
+        .and(warp::path("register"))
+        .and(warp::path("options"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_register_options);
+
+    let register_verify = warp::path("passkeys")
+        .and(warp::path("register"))
+        .and(warp::path("verify"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_register_verify);
+
+    let login_options = warp::path("passkeys")
+        .and(warp::path("login"))
+        .and(warp::path("options"))
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "passkey_login_options"))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_login_options);
+
+    let login_verify = warp::path("passkeys")
+        .and(warp::path("login"))
+        .and(warp::path("verify"))
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "passkey_login"))
+        .and(warp::body::json())
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_login_verify);
+
+    let remove = warp::path("passkeys")
+        .and(warp::path("remove"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_remove_passkey);
+
+    list.or(register_options).or(register_verify).or(login_options).or(login_verify).or(remove)
+}
+
+fn passkeys(state: &AppState) -> Result<&Passkeys, Rejection> {
+    match &state.passkeys {
+        Some(passkeys) => Ok(passkeys),
+        None => Err(ApiError::PasskeysDisabled.into()),
+    }
+}
+
+pub fn passkey_info(passkey: &PasskeyData) -> PasskeyInfo {
+    PasskeyInfo {
+        id: passkey.id.clone(),
+        name: passkey.name.clone(),
+        created_at: passkey.created_at.clone(),
+        last_used_at: passkey.last_used_at.clone(),
+    }
+}
+
+// A passkey signs in on its own, so adding or removing one takes more than a session.
+// Wrong answers count toward the lockout like they do at login.
+async fn reauthenticate(state: &AppState, mut user_data: FullUserData, req: &PasskeyReauth) -> Result<FullUserData, Rejection> {
+    check_not_locked(&user_data)?;
+
+    if let Some(password) = &req.password {
+        if !matches!(state.passwords.verify(password, &user_data.password).await, PasswordCheck::Invalid) {
+            return Ok(user_data);
+        }
+    }
+
+    let two_factor_enabled = user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled);
+    if let (Some(code), true) = (&req.code, two_factor_enabled) {
+        if check_code(state, &mut user_data, code)? {
+            return Ok(user_data);
+        }
+    }
+
+    count_failed_login(&state.config.rate_limit, &mut user_data);
+    state.store.write_user_data(user_data)?;
+    Err(ApiError::InvalidCredentials.into())
+}
+
+async fn handle_list_passkeys(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let passkeys: Vec<PasskeyInfo> = user_data.passkeys.iter().map(passkey_info).collect();
+    Ok(warp::reply::json(&passkeys))
+}
+
+async fn handle_register_options(auth: AuthContext, req: PasskeyReauth, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let passkeys = passkeys(&state)?;
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let user_data = reauthenticate(&state, user_data, &req).await?;
+
+    // Stops the same authenticator from being registered twice
+    let exclude_credentials = user_data.passkeys.iter().map(|passkey| passkey.passkey.cred_id().clone()).collect();
+    let started = passkeys.webauthn.start_passkey_registration(
+        WebauthnUuid::from_u128(user_data.guid),
+        &user_data.username,
+        &user_data.username,
+        Some(exclude_credentials),
+    );
+    // Spends the two-factor code, if that is what was used
+    state.store.write_user_data(user_data)?;
+    let (options, registration) = match started {
+        Ok(started) => started,
+        Err(err) => return Err(ApiError::Internal(format!("Failed to start passkey registration: {}", err)).into()),
+    };
+
+    let ceremony_id = passkeys.begin(Ceremony::Registration { username: auth.username, state: registration })?;
+    Ok(warp::reply::json(&PasskeyCeremony { ceremony_id, options }))
+}
+
+async fn handle_register_verify(auth: AuthContext, req: PasskeyRegistrationFinish, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let passkeys = passkeys(&state)?;
+    let registration = match passkeys.take(&req.ceremony_id) {
+        Some(Ceremony::Registration { username, state }) if username == auth.username => state,
+        _ => return Err(ApiError::InvalidToken.into()),
+    };
+
+    let passkey = match passkeys.webauthn.finish_passkey_registration(&req.credential, &registration) {
+        Ok(passkey) => passkey,
+        Err(_) => return Err(ApiError::validation("credential", "Passkey could not be verified").into()),
+    };
+
+    let id = URL_SAFE_NO_PAD.encode(passkey.cred_id());
+    match state.store.passkey_owner(&id) {
+        Err(StoreError::NotFound) => {}
+        Ok(_) => return Err(ApiError::validation("credential", "Passkey is already registered").into()),
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut user_data = state.store.read_user_data(&auth.username)?;
+    let name = match req.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => format!("Passkey {}", user_data.passkeys.len() + 1),
+    };
+    let passkey = PasskeyData { id, name, passkey, created_at: timestamp(Utc::now()), last_used_at: None };
+    let info = passkey_info(&passkey);
+
+    user_data.passkeys.push(passkey);
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&info))
+}
+
+async fn handle_login_options(req: PasskeyLoginStart, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let passkeys = passkeys(&state)?;
+
+    let challenge_token = match req.challenge_token {
+        Some(challenge_token) => challenge_token,
+        None => {
+            let (options, authentication) = match passkeys.webauthn.start_discoverable_authentication() {
+                Ok(started) => started,
+                Err(err) => return Err(ApiError::Internal(format!("Failed to start passkey login: {}", err)).into()),
+            };
+
+            let ceremony_id = passkeys.begin(Ceremony::Passwordless(authentication))?;
+            return Ok(warp::reply::json(&PasskeyCeremony { ceremony_id, options }));
+        }
+    };
+
+    // Second factor: the password was already checked when the challenge was issued
+    let claims: TwoFactorChallengeClaims = match state.tokens.verify(CHALLENGE_PURPOSE, &challenge_token) {
+        Some(claims) => claims,
+        None => return Err(ApiError::InvalidToken.into()),
+    };
+    let user_data = match state.store.read_user_data(&claims.username) {
+        Ok(user_data) if user_data.guid == claims.guid && !user_data.passkeys.is_empty()
+            && user_data.login_challenge.as_ref().is_some_and(|challenge| challenge.id == claims.challenge_id) => user_data,
+        Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let credentials: Vec<_> = user_data.passkeys.iter().map(|passkey| passkey.passkey.clone()).collect();
+    let (options, authentication) = match passkeys.webauthn.start_passkey_authentication(&credentials) {
+        Ok(started) => started,
+        Err(err) => return Err(ApiError::Internal(format!("Failed to start passkey login: {}", err)).into()),
+    };
+
+    let ceremony = Ceremony::SecondFactor {
+        username: user_data.username.to_lowercase(),
+        guid: user_data.guid,
+        device: claims.device,
+        state: authentication,
+    };
+    let ceremony_id = passkeys.begin(ceremony)?;
+    Ok(warp::reply::json(&PasskeyCeremony { ceremony_id, options }))
+}
+
+async fn handle_login_verify(req: PasskeyLoginFinish, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let result = passkey_login(req, &client, state.clone()).await;
+    // Which account a failed ceremony was for isn't known. Successes are recorded by start_session.
+    if result.is_err() {
+        state.audit.record(AuditEvent::Login, None, &client, &result, None);
+    }
+    result
+}
+
+async fn passkey_login(req: PasskeyLoginFinish, client: &ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let passkeys = passkeys(&state)?;
+
+    let (mut user_data, result, device) = match passkeys.take(&req.ceremony_id) {
+        Some(Ceremony::SecondFactor { username, guid, device, state: authentication }) => {
+            let user_data = match state.store.read_user_data(&username) {
+                Ok(user_data) if user_data.guid == guid => user_data,
+                Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+                Err(err) => return Err(err.into()),
+            };
+
+            match passkeys.webauthn.finish_passkey_authentication(&req.credential, &authentication) {
+                Ok(result) => (user_data, result, device),
+                Err(_) => return Err(ApiError::InvalidCredentials.into()),
+            }
+        }
+        Some(Ceremony::Passwordless(authentication)) => {
+            // The authenticator names the account through the credential id and user handle
+            let (user_handle, credential_id) = match passkeys.webauthn.identify_discoverable_authentication(&req.credential) {
+                Ok(identified) => identified,
+                Err(_) => return Err(ApiError::InvalidCredentials.into()),
+            };
+            let user_data = match state.store.passkey_owner(&URL_SAFE_NO_PAD.encode(credential_id)).and_then(|username| state.store.read_user_data(&username)) {
+                Ok(user_data) if WebauthnUuid::from_u128(user_data.guid) == user_handle => user_data,
+                Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidCredentials.into()),
+                Err(err) => return Err(err.into()),
+            };
+
+            let credentials: Vec<DiscoverableKey> = user_data.passkeys.iter().map(|passkey| DiscoverableKey::from(&passkey.passkey)).collect();
+            let result = match passkeys.webauthn.finish_discoverable_authentication(&req.credential, authentication, &credentials) {
+                Ok(result) => result,
+                Err(_) => return Err(ApiError::InvalidCredentials.into()),
+            };
+
+            // A passkey replaces the password here, so the same account checks apply
+            if state.config.auth.require_verified_email && !user_data.email_verified {
+                return Err(ApiError::EmailNotVerified.into());
+            }
+
+            (user_data, result, req.device)
+        }
+        Some(Ceremony::Registration { .. }) | None => return Err(ApiError::InvalidToken.into()),
+    };
+
+    // Keeps the signature counter current so cloned authenticators can be spotted
+    let now = timestamp(Utc::now());
+    for passkey in user_data.passkeys.iter_mut() {
+        if passkey.passkey.update_credential(&result).is_some() {
+            passkey.last_used_at = Some(now.clone());
+        }
+    }
+
+    let username = user_data.username.clone();
+    let guid = user_data.guid;
+    state.store.write_user_data(user_data)?;
+
+    let response = start_session(&state, &username, guid, device, client, "passkey")?;
+    Ok(warp::reply::json(&response))
+}
+
+async fn handle_remove_passkey(auth: AuthContext, req: RemovePasskey, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let mut user_data = reauthenticate(&state, user_data, &req.reauth).await?;
+    let index = match user_data.passkeys.iter().position(|passkey| passkey.id == req.id) {
+        Some(index) => index,
+        None => return Err(ApiError::NotFound("passkey").into()),
+    };
+
+    user_data.passkeys.remove(index);
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&"Passkey removed"))
+}
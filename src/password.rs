@@ -0,0 +1,142 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use crypto_hash::{hex_digest, Algorithm as HashAlgorithm};
+
+pub enum PasswordCheck {
+    Valid,
+    /// The password matched, but the stored hash is a legacy bare SHA256 digest or
+    /// uses weaker Argon2 parameters than the current ones and should be replaced.
+    NeedsRehash,
+    Invalid,
+}
+
+/// Argon2id hashing into PHC strings (`$argon2id$v=19$m=..,t=..,p=..$salt$hash`),
+/// each with its own random salt. Hashing takes tens of milliseconds by design, so it
+/// runs on tokio's blocking threads rather than holding up the ones serving requests.
+#[derive(Clone, Default)]
+pub struct PasswordHashing {
+    params: Params,
+}
+
+impl PasswordHashing {
+    pub fn new(memory_kib: u32, iterations: u32, parallelism: u32) -> Result<PasswordHashing, String> {
+        match Params::new(memory_kib, iterations, parallelism, None) {
+            Ok(params) => Ok(PasswordHashing { params }),
+            Err(err) => Err(format!("Invalid argon2 parameters: {}", err)),
+        }
+    }
+
+    fn argon2(&self) -> Argon2<'static> {
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone())
+    }
+
+    pub async fn hash(&self, password: &str) -> Result<String, String> {
+        let (hashing, password) = (self.clone(), password.to_string());
+        match tokio::task::spawn_blocking(move || hashing.hash_now(&password)).await {
+            Ok(result) => result,
+            Err(err) => Err(format!("Failed to hash password: {}", err)),
+        }
+    }
+
+    // A check that never finished can't have matched
+    pub async fn verify(&self, password: &str, stored: &str) -> PasswordCheck {
+        let (hashing, password, stored) = (self.clone(), password.to_string(), stored.to_string());
+        tokio::task::spawn_blocking(move || hashing.verify_now(&password, &stored))
+            .await
+            .unwrap_or(PasswordCheck::Invalid)
+    }
+
+    fn hash_now(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        match self.argon2().hash_password(password.as_bytes(), &salt) {
+            Ok(hash) => Ok(hash.to_string()),
+            Err(err) => Err(format!("Failed to hash password: {}", err)),
+        }
+    }
+
+    fn verify_now(&self, password: &str, stored: &str) -> PasswordCheck {
+        let parsed = match PasswordHash::new(stored) {
+            Ok(parsed) => parsed,
+            // Accounts created before Argon2 hold a hex encoded, unsalted SHA256 digest
+            Err(_) => {
+                return if is_legacy_sha256(stored) && hex_digest(HashAlgorithm::SHA256, password.as_bytes()) == stored {
+                    PasswordCheck::NeedsRehash
+                } else {
+                    PasswordCheck::Invalid
+                };
+            }
+        };
+
+        if self.argon2().verify_password(password.as_bytes(), &parsed).is_err() {
+            return PasswordCheck::Invalid;
+        }
+
+        match Params::try_from(&parsed) {
+            Ok(params)
+                if parsed.algorithm == Algorithm::Argon2id.ident()
+                    && params.m_cost() >= self.params.m_cost()
+                    && params.t_cost() >= self.params.t_cost()
+                    && params.p_cost() >= self.params.p_cost() =>
+            {
+                PasswordCheck::Valid
+            }
+            _ => PasswordCheck::NeedsRehash,
+        }
+    }
+}
+
+fn is_legacy_sha256(stored: &str) -> bool {
+    stored.len() == 64 && stored.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The smallest costs Argon2 accepts, to keep the tests fast
+    fn hashing() -> PasswordHashing {
+        PasswordHashing::new(8, 1, 1).unwrap()
+    }
+
+    #[test]
+    fn verifies_argon2_hashes() {
+        let passwords = hashing();
+        let stored = passwords.hash_now("correct horse").unwrap();
+
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(matches!(passwords.verify_now("correct horse", &stored), PasswordCheck::Valid));
+        assert!(matches!(passwords.verify_now("wrong horse", &stored), PasswordCheck::Invalid));
+    }
+
+    #[test]
+    fn salts_every_hash() {
+        let passwords = hashing();
+        assert_ne!(passwords.hash_now("correct horse").unwrap(), passwords.hash_now("correct horse").unwrap());
+    }
+
+    #[test]
+    fn asks_for_a_rehash_of_legacy_sha256_digests() {
+        let passwords = hashing();
+        let legacy = hex_digest(HashAlgorithm::SHA256, b"correct horse");
+
+        assert!(matches!(passwords.verify_now("correct horse", &legacy), PasswordCheck::NeedsRehash));
+        assert!(matches!(passwords.verify_now("wrong horse", &legacy), PasswordCheck::Invalid));
+    }
+
+    #[test]
+    fn asks_for_a_rehash_of_weaker_argon2_parameters() {
+        let stored = hashing().hash_now("correct horse").unwrap();
+        let stronger = PasswordHashing::new(16, 2, 1).unwrap();
+
+        assert!(matches!(stronger.verify_now("correct horse", &stored), PasswordCheck::NeedsRehash));
+        assert!(matches!(stronger.verify_now("wrong horse", &stored), PasswordCheck::Invalid));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        let passwords = hashing();
+        assert!(matches!(passwords.verify_now("correct horse", ""), PasswordCheck::Invalid));
+        assert!(matches!(passwords.verify_now("correct horse", "correct horse"), PasswordCheck::Invalid));
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::de::DeserializeOwned;
+use warp::{Filter, Rejection};
+
+use crate::audit::client_ip;
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::errors::ApiError;
+use crate::store::UserStore;
+use crate::utils::{email_lookup, timestamp};
+use crate::{with_state, AppState, FullUserData, LoginRequest, MagicLinkRequest, OTPSubmit, RegisterUser, RequestPassword};
+
+// Once there are this many buckets, each new one first evicts an old one
+const MAX_BUCKETS: usize = 10_000;
+// How many of the oldest buckets eviction looks through for one that has refilled
+const EVICT_BATCH: usize = 32;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // When the bucket is back to its burst size if it isn't used again
+    full_at: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    // Keys in the order their buckets were made, oldest first
+    order: VecDeque<String>,
+}
+
+impl Buckets {
+    // Makes room for one more bucket with a bounded amount of work: the first of the
+    // oldest few buckets that has refilled goes, or the oldest if they're all in use.
+    fn evict(&mut self, now: Instant) {
+        let refilled = self.order.iter().take(EVICT_BATCH).position(|key| self.by_key.get(key).is_none_or(|bucket| bucket.full_at <= now));
+        if let Some(key) = self.order.remove(refilled.unwrap_or(0)) {
+            self.by_key.remove(&key);
+        }
+    }
+}
+
+/// Token buckets keyed by endpoint and IP or account. Each request takes a token,
+/// and tokens come back at a steady rate up to the burst size. Kept in memory, so
+/// limits start over when the server restarts.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter { buckets: Mutex::new(Buckets::default()) }
+    }
+
+    /// Takes a token for `key`, or returns how many seconds until one is available.
+    pub fn check(&self, key: &str, limit: &BucketConfig) -> Result<(), u64> {
+        let now = Instant::now();
+        let burst = limit.burst as f64;
+        let per_second = limit.per_minute as f64 / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.by_key.contains_key(key) {
+            if buckets.by_key.len() >= MAX_BUCKETS {
+                buckets.evict(now);
+            }
+            buckets.order.push_back(key.to_string());
+            buckets.by_key.insert(key.to_string(), Bucket { tokens: burst, updated: now, full_at: now });
+        }
+
+        let bucket = buckets.by_key.get_mut(key).unwrap();
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second).min(burst);
+        bucket.updated = now;
+
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / per_second).ceil() as u64)
+        };
+        bucket.full_at = now + std::time::Duration::from_secs_f64((burst - bucket.tokens) / per_second);
+        result
+    }
+}
+
+/// Request bodies naming the account they act on, so attempts against one account
+/// are limited however many addresses they come from.
+pub trait AccountKey {
+    fn account_key(&self, store: &dyn UserStore) -> String;
+}
+
+impl AccountKey for LoginRequest {
+    // Logins by username and by email address share the account's bucket; names that
+    // aren't an account's keep their own
+    fn account_key(&self, store: &dyn UserStore) -> String {
+        email_lookup(store, &self.username).unwrap_or_else(|_| self.username.clone()).to_lowercase()
+    }
+}
+
+impl AccountKey for RegisterUser {
+    fn account_key(&self, _store: &dyn UserStore) -> String {
+        self.email.to_lowercase()
+    }
+}
+
+impl AccountKey for RequestPassword {
+    fn account_key(&self, _store: &dyn UserStore) -> String {
+        self.email.to_lowercase()
+    }
+}
+
+impl AccountKey for OTPSubmit {
+    fn account_key(&self, _store: &dyn UserStore) -> String {
+        self.email.to_lowercase()
+    }
+}
+
+impl AccountKey for MagicLinkRequest {
+    fn account_key(&self, _store: &dyn UserStore) -> String {
+        self.email.to_lowercase()
+    }
+}
+
+/// Rejects the request with a 429 once the client's IP has used up its tokens for `scope`.
+pub fn limit_ip(state: Arc<AppState>, scope: &'static str) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_ip(state.clone())
+        .and(with_state(state))
+        .and_then(move |ip: Option<IpAddr>, state: Arc<AppState>| async move {
+            let config = &state.config.rate_limit;
+            if let (true, Some(ip)) = (config.enabled, ip) {
+                let key = format!("{}:ip:{}", scope, ip);
+                if let Err(retry_after) = state.rate_limiter.check(&key, &config.ip) {
+                    return Err(ApiError::RateLimited(retry_after).into());
+                }
+            }
+
+            Ok::<(), Rejection>(())
+        })
+        .untuple_one()
+}
+
+/// Parses the Json body and rejects the request with a 429 once the account it names
+/// has used up its tokens for `scope`. Takes the place of `warp::body::json()`.
+pub fn json_limited_by_account<T>(state: Arc<AppState>, scope: &'static str) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: AccountKey + DeserializeOwned + Send + 'static,
+{
+    warp::body::json::<T>()
+        .and(with_state(state))
+        .and_then(move |body: T, state: Arc<AppState>| async move {
+            let config = &state.config.rate_limit;
+            if config.enabled {
+                let key = format!("{}:account:{}", scope, body.account_key(state.store.as_ref()));
+                if let Err(retry_after) = state.rate_limiter.check(&key, &config.account) {
+                    return Err(ApiError::RateLimited(retry_after).into());
+                }
+            }
+
+            Ok::<T, Rejection>(body)
+        })
+}
+
+/// When an account with this many failed logins in a row unlocks, if it is locked at all.
+/// Each failure past the threshold doubles the lockout, up to the configured maximum.
+pub fn lockout_until(config: &RateLimitConfig, failed_logins: u32) -> Option<DateTime<Utc>> {
+    if !config.enabled || failed_logins < config.lockout_after_failures {
+        return None;
+    }
+
+    let doublings = (failed_logins - config.lockout_after_failures).min(20);
+    let seconds = config.lockout_base_seconds.saturating_mul(1 << doublings).min(config.lockout_max_seconds);
+    Some(Utc::now() + Duration::seconds(seconds))
+}
+
+/// Counts a wrong password or code toward the account's lockout. The caller saves the
+/// user data.
+pub fn count_failed_login(config: &RateLimitConfig, user_data: &mut FullUserData) {
+    user_data.failed_login_count = user_data.failed_login_count.saturating_add(1);
+    user_data.locked_until = lockout_until(config, user_data.failed_login_count).map(timestamp);
+    if let Some(locked_until) = &user_data.locked_until {
+        println!("Locked {} until {} after {} failed logins", user_data.username, locked_until, user_data.failed_login_count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lockout_seconds(config: &RateLimitConfig, failed_logins: u32) -> Option<i64> {
+        let until = lockout_until(config, failed_logins)?;
+        Some(((until - Utc::now()).num_milliseconds() + 500) / 1000)
+    }
+
+    #[test]
+    fn allows_the_burst_then_asks_to_wait() {
+        let limiter = RateLimiter::new();
+        let limit = BucketConfig { burst: 3, per_minute: 6 };
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check("login:1.2.3.4", &limit), Ok(()));
+        }
+        assert_eq!(limiter.check("login:1.2.3.4", &limit), Err(10));
+    }
+
+    #[test]
+    fn keeps_a_bucket_per_key() {
+        let limiter = RateLimiter::new();
+        let limit = BucketConfig { burst: 1, per_minute: 1 };
+
+        assert_eq!(limiter.check("login:1.2.3.4", &limit), Ok(()));
+        assert!(limiter.check("login:1.2.3.4", &limit).is_err());
+        assert_eq!(limiter.check("login:5.6.7.8", &limit), Ok(()));
+        assert_eq!(limiter.check("register:1.2.3.4", &limit), Ok(()));
+    }
+
+    #[test]
+    fn evicts_the_oldest_buckets_once_full() {
+        let limiter = RateLimiter::new();
+        let limit = BucketConfig { burst: 1, per_minute: 1 };
+
+        for n in 0..MAX_BUCKETS + 10 {
+            assert_eq!(limiter.check(&format!("login:{}", n), &limit), Ok(()));
+        }
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS);
+        assert_eq!(buckets.order.len(), MAX_BUCKETS);
+        assert!(!buckets.by_key.contains_key("login:0"));
+        assert!(buckets.by_key.contains_key("login:10"));
+    }
+
+    #[test]
+    fn locks_after_the_threshold_and_doubles_up_to_the_maximum() {
+        let config = RateLimitConfig::default();
+
+        assert_eq!(lockout_seconds(&config, 0), None);
+        assert_eq!(lockout_seconds(&config, 4), None);
+        assert_eq!(lockout_seconds(&config, 5), Some(60));
+        assert_eq!(lockout_seconds(&config, 6), Some(120));
+        assert_eq!(lockout_seconds(&config, 7), Some(240));
+        assert_eq!(lockout_seconds(&config, 11), Some(3600));
+    }
+
+    #[test]
+    fn never_locks_when_disabled() {
+        let config = RateLimitConfig { enabled: false, ..RateLimitConfig::default() };
+        assert_eq!(lockout_seconds(&config, 100), None);
+    }
+
+    #[test]
+    fn saturates_long_lockouts() {
+        let max_seconds = 10 * 365 * 86400;
+        let config = RateLimitConfig { lockout_base_seconds: i64::MAX / 2, lockout_max_seconds: max_seconds, ..RateLimitConfig::default() };
+
+        assert_eq!(lockout_seconds(&config, 5), Some(max_seconds));
+        assert_eq!(lockout_seconds(&config, u32::MAX), Some(max_seconds));
+    }
+}
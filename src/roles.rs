@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use uuid::Uuid;
+use warp::{Filter, Rejection};
+
+use crate::auth::{with_auth, AuthContext};
+use crate::errors::{ApiError, StoreError};
+use crate::utils::email_lookup;
+use crate::{AppState, FullUserData};
+
+/// Everything roles can grant. Routes ask for one of these, never for a role, so
+/// what each role may do is left to the [roles] config.
+pub const PERMISSIONS: &[&str] = &[
+    // List and view accounts
+    "users:read",
+    // Lock, unlock, sign out, force a password reset, change email
+    "users:manage",
+    "users:delete",
+    // Grant and revoke roles and permissions
+    "roles:manage",
+    // Query the audit log for any account
+    "audit:read",
+    // Register OAuth clients that can sign users in
+    "oauth_clients:create",
+];
+
+/// Held by every account without being stored
+pub const DEFAULT_ROLE: &str = "user";
+/// Given to admin.bootstrap_admin
+pub const ADMIN_ROLE: &str = "admin";
+// Written once the bootstrap admin has been granted its role, holding its guid
+const BOOTSTRAP_MARKER_FILE: &str = "bootstrap_admin.txt";
+
+/// A user's roles and every permission they add up to, along with any granted directly.
+#[derive(Debug, Clone, Default)]
+pub struct Grants {
+    pub roles: Vec<String>,
+    pub permissions: Vec<String>,
+}
+
+impl Grants {
+    pub fn of(state: &AppState, user_data: &FullUserData) -> Grants {
+        let mut roles = vec![DEFAULT_ROLE.to_string()];
+        roles.extend(user_data.roles.iter().filter(|role| *role != DEFAULT_ROLE).cloned());
+
+        // Roles since dropped from the config grant nothing
+        let mut permissions: Vec<String> = roles.iter()
+            .filter_map(|role| state.config.roles.permissions.get(role))
+            .flatten()
+            .chain(user_data.permissions.iter())
+            .cloned()
+            .collect();
+        permissions.sort();
+        permissions.dedup();
+
+        Grants { roles, permissions }
+    }
+
+    pub fn allows(&self, permission: &str) -> bool {
+        self.permissions.iter().any(|granted| granted == permission)
+    }
+}
+
+/// Like `with_auth`, but also rejects callers whose roles don't grant `permission`.
+pub fn require_permission(state: Arc<AppState>, permission: &'static str) -> impl Filter<Extract = (AuthContext,), Error = Rejection> + Clone {
+    with_auth(state).and_then(move |auth: AuthContext| async move {
+        if auth.grants.allows(permission) {
+            Ok(auth)
+        } else {
+            Err(Rejection::from(ApiError::PermissionDenied(permission)))
+        }
+    })
+}
+
+/// Gives admin.bootstrap_admin the admin role at startup, so a fresh server can get its
+/// first admin without editing any files. Only an account with a verified email qualifies,
+/// and it happens once: a marker file in the data directory stops it from undoing a
+/// later revoke or promoting whoever takes the name afterwards.
+pub fn bootstrap_admin(state: &AppState) -> Result<(), StoreError> {
+    let account = match &state.config.admin.bootstrap_admin {
+        Some(account) => account,
+        None => return Ok(()),
+    };
+
+    let marker = state.config.storage.data_dir.join(BOOTSTRAP_MARKER_FILE);
+    if marker.exists() {
+        return Ok(());
+    }
+
+    let user_data = email_lookup(state.store.as_ref(), account).and_then(|username| state.store.read_user_data(&username));
+    let mut user_data = match user_data {
+        Ok(user_data) => user_data,
+        Err(StoreError::NotFound) => {
+            println!("Bootstrap admin {} has not registered yet", account);
+            return Ok(());
+        }
+        Err(err) => return Err(err),
+    };
+
+    if !user_data.email_verified {
+        println!("Bootstrap admin {} needs a verified email address before it gets the {} role", user_data.username, ADMIN_ROLE);
+        return Ok(());
+    }
+
+    let guid = user_data.guid;
+    if !user_data.roles.iter().any(|role| role == ADMIN_ROLE) {
+        println!("Granting the {} role to {}", ADMIN_ROLE, user_data.username);
+        user_data.roles.push(ADMIN_ROLE.to_string());
+        state.store.write_user_data(user_data)?;
+    }
+
+    match std::fs::write(&marker, Uuid::from_u128(guid).to_string()) {
+        Ok(_) => Ok(()),
+        Err(err) => Err(StoreError::Io(format!("Failed to write {}: {}", marker.display(), err))),
+    }
+}
@@ -0,0 +1,845 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::Utc;
+use rusqlite::types::Type;
+use rusqlite::{named_params, params, Connection, OptionalExtension, Row};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::StoreError;
+use crate::store::UserStore;
+use crate::utils::timestamp;
+use crate::{AuthCodeData, FullUserData, OAuthClient, OTPData, RefreshTokenData, SessionData};
+
+// Applied in order at startup; the index of the last applied entry is kept in
+// `PRAGMA user_version`. Never edit a shipped migration, append a new one.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE users (
+        username_lower TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL,
+        guid TEXT NOT NULL,
+        email TEXT,
+        avatar TEXT,
+        password TEXT NOT NULL
+    );
+    CREATE UNIQUE INDEX users_email_lower ON users (lower(email));
+    CREATE TABLE sessions (
+        username_lower TEXT PRIMARY KEY NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        session_key TEXT NOT NULL
+    );
+    CREATE TABLE otps (
+        username_lower TEXT PRIMARY KEY NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        otp TEXT NOT NULL,
+        date TEXT NOT NULL
+    );",
+    "DROP TABLE sessions;
+    CREATE TABLE sessions (
+        id TEXT PRIMARY KEY NOT NULL,
+        username_lower TEXT NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        session_key TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        last_seen_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        device TEXT,
+        ip TEXT
+    );
+    CREATE INDEX sessions_username_lower ON sessions (username_lower);",
+    "ALTER TABLE users ADD COLUMN pending_email_change TEXT;",
+    "ALTER TABLE users ADD COLUMN email_verified INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN verification_sent_at TEXT;",
+    "CREATE UNIQUE INDEX sessions_session_key ON sessions (session_key);",
+    "CREATE TABLE refresh_tokens (
+        token_hash TEXT PRIMARY KEY NOT NULL,
+        family_id TEXT NOT NULL,
+        username_lower TEXT NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        created_at TEXT NOT NULL,
+        expires_at TEXT NOT NULL,
+        used INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX refresh_tokens_username_lower ON refresh_tokens (username_lower);",
+    "ALTER TABLE users ADD COLUMN oauth_consents TEXT;
+    CREATE TABLE oauth_clients (
+        client_id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        secret_hash TEXT,
+        redirect_uris TEXT NOT NULL,
+        owner TEXT NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE auth_codes (
+        code_hash TEXT PRIMARY KEY NOT NULL,
+        client_id TEXT NOT NULL REFERENCES oauth_clients (client_id) ON DELETE CASCADE,
+        username_lower TEXT NOT NULL REFERENCES users (username_lower) ON DELETE CASCADE,
+        redirect_uri TEXT NOT NULL,
+        scope TEXT NOT NULL,
+        code_challenge TEXT NOT NULL,
+        nonce TEXT,
+        expires_at TEXT NOT NULL
+    );",
+    "ALTER TABLE users ADD COLUMN two_factor TEXT;",
+    "ALTER TABLE users ADD COLUMN passkeys TEXT;",
+    // Codes used to be kept in plain text, outstanding ones are dropped rather than carried over
+    "DELETE FROM otps;
+    ALTER TABLE otps RENAME COLUMN otp TO otp_hash;
+    ALTER TABLE otps ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE users ADD COLUMN magic_link_id TEXT;",
+    "ALTER TABLE users ADD COLUMN failed_login_count INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE users ADD COLUMN locked_until TEXT;",
+    // Times used to be saved as server local time without an offset. The 'utc' modifier reads
+    // them as local time, like they were written; times inside Json columns are converted
+    // when they are read.
+    "UPDATE otps SET date = strftime('%Y-%m-%dT%H:%M:%SZ', date, 'utc') WHERE date NOT LIKE '%Z';
+    UPDATE sessions SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'utc') WHERE created_at NOT LIKE '%Z';
+    UPDATE sessions SET last_seen_at = strftime('%Y-%m-%dT%H:%M:%SZ', last_seen_at, 'utc') WHERE last_seen_at NOT LIKE '%Z';
+    UPDATE sessions SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, 'utc') WHERE expires_at NOT LIKE '%Z';
+    UPDATE refresh_tokens SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'utc') WHERE created_at NOT LIKE '%Z';
+    UPDATE refresh_tokens SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, 'utc') WHERE expires_at NOT LIKE '%Z';
+    UPDATE oauth_clients SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', created_at, 'utc') WHERE created_at NOT LIKE '%Z';
+    UPDATE auth_codes SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', expires_at, 'utc') WHERE expires_at NOT LIKE '%Z';
+    UPDATE users SET verification_sent_at = strftime('%Y-%m-%dT%H:%M:%SZ', verification_sent_at, 'utc') WHERE verification_sent_at NOT LIKE '%Z';
+    UPDATE users SET locked_until = strftime('%Y-%m-%dT%H:%M:%SZ', locked_until, 'utc') WHERE locked_until NOT LIKE '%Z';",
+    "ALTER TABLE users ADD COLUMN created_at TEXT;
+    ALTER TABLE users ADD COLUMN updated_at TEXT;
+    ALTER TABLE users ADD COLUMN last_login_at TEXT;
+    ALTER TABLE users ADD COLUMN last_login_ip TEXT;
+    ALTER TABLE users ADD COLUMN password_changed_at TEXT;",
+    "ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;",
+    "ALTER TABLE users ADD COLUMN roles TEXT;
+    ALTER TABLE users ADD COLUMN permissions TEXT;",
+    "ALTER TABLE users ADD COLUMN deletion_scheduled_at TEXT;",
+    "ALTER TABLE users ADD COLUMN login_challenge TEXT;",
+];
+
+/// Stores everything in a single SQLite database file.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<SqliteStore> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "foreign_keys", true)?;
+        migrate(&mut conn)?;
+
+        Ok(SqliteStore { conn: Mutex::new(conn) })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", index + 1)?;
+        tx.commit()?;
+        println!("Applied database migration {}", index + 1);
+    }
+
+    Ok(())
+}
+
+// Nested records are kept as Json text columns
+fn to_json_column<T: Serialize>(value: &Option<T>) -> Option<String> {
+    value.as_ref().and_then(|value| serde_json::to_string(value).ok())
+}
+
+// A value that doesn't decode is an error rather than a missing one, so a damaged
+// two_factor column can't quietly turn two-factor authentication off
+fn from_json_column<T: DeserializeOwned>(row: &Row, column: &str) -> rusqlite::Result<Option<T>> {
+    let value: Option<String> = row.get(column)?;
+    value
+        .map(|value| serde_json::from_str(&value).map_err(|err| conversion_error(row, column, err)))
+        .transpose()
+}
+
+fn conversion_error(row: &Row, column: &str, err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> rusqlite::Error {
+    let index = row.as_ref().column_index(column).unwrap_or_default();
+    rusqlite::Error::FromSqlConversionFailure(index, Type::Text, err.into())
+}
+
+fn row_to_user(row: &Row) -> rusqlite::Result<FullUserData> {
+    let guid: String = row.get("guid")?;
+    Ok(FullUserData {
+        username: row.get("username")?,
+        guid: guid.parse().map_err(|err| conversion_error(row, "guid", err))?,
+        email: row.get("email")?,
+        avatar: row.get("avatar")?,
+        password: row.get("password")?,
+        pending_email_change: from_json_column(row, "pending_email_change")?,
+        email_verified: row.get("email_verified")?,
+        verification_sent_at: row.get("verification_sent_at")?,
+        oauth_consents: from_json_column(row, "oauth_consents")?.unwrap_or_default(),
+        two_factor: from_json_column(row, "two_factor")?,
+        passkeys: from_json_column(row, "passkeys")?.unwrap_or_default(),
+        magic_link_id: row.get("magic_link_id")?,
+        failed_login_count: row.get("failed_login_count")?,
+        locked_until: row.get("locked_until")?,
+        disabled: row.get("disabled")?,
+        roles: from_json_column(row, "roles")?.unwrap_or_default(),
+        permissions: from_json_column(row, "permissions")?.unwrap_or_default(),
+        created_at: row.get("created_at")?,
+        updated_at: row.get("updated_at")?,
+        last_login_at: row.get("last_login_at")?,
+        last_login_ip: row.get("last_login_ip")?,
+        password_changed_at: row.get("password_changed_at")?,
+        deletion_scheduled_at: row.get("deletion_scheduled_at")?,
+        login_challenge: from_json_column(row, "login_challenge")?,
+    })
+}
+
+// Runs an INSERT or UPDATE of the users table with every column bound by name
+fn execute_user(conn: &Connection, sql: &str, user_data: &FullUserData) -> rusqlite::Result<usize> {
+    conn.execute(
+        sql,
+        named_params! {
+            ":username_lower": user_data.username.to_lowercase(),
+            ":username": user_data.username,
+            ":guid": user_data.guid.to_string(),
+            ":email": user_data.email,
+            ":avatar": user_data.avatar,
+            ":password": user_data.password,
+            ":pending_email_change": to_json_column(&user_data.pending_email_change),
+            ":email_verified": user_data.email_verified,
+            ":verification_sent_at": user_data.verification_sent_at,
+            ":oauth_consents": serde_json::to_string(&user_data.oauth_consents).ok(),
+            ":two_factor": to_json_column(&user_data.two_factor),
+            ":passkeys": serde_json::to_string(&user_data.passkeys).ok(),
+            ":magic_link_id": user_data.magic_link_id,
+            ":failed_login_count": user_data.failed_login_count,
+            ":locked_until": user_data.locked_until,
+            ":disabled": user_data.disabled,
+            ":roles": serde_json::to_string(&user_data.roles).ok(),
+            ":permissions": serde_json::to_string(&user_data.permissions).ok(),
+            ":created_at": user_data.created_at,
+            ":updated_at": user_data.updated_at,
+            ":last_login_at": user_data.last_login_at,
+            ":last_login_ip": user_data.last_login_ip,
+            ":password_changed_at": user_data.password_changed_at,
+            ":deletion_scheduled_at": user_data.deletion_scheduled_at,
+            ":login_challenge": to_json_column(&user_data.login_challenge),
+        },
+    )
+}
+
+// Unique constraint failures name the column or index that clashed, a foreign key
+// failure means the user the rows belong to is gone
+fn store_error(context: &str, err: rusqlite::Error) -> StoreError {
+    match &err {
+        rusqlite::Error::SqliteFailure(failure, Some(message)) if failure.code == rusqlite::ErrorCode::ConstraintViolation => {
+            if message.starts_with("FOREIGN KEY") {
+                StoreError::NotFound
+            } else if message.contains("email") {
+                StoreError::EmailTaken
+            } else {
+                StoreError::UsernameTaken
+            }
+        }
+        _ => StoreError::Io(format!("{}: {}", context, err)),
+    }
+}
+
+fn insert_refresh_token(conn: &Connection, token: &RefreshTokenData) -> rusqlite::Result<usize> {
+    conn.execute(
+        "INSERT INTO refresh_tokens (token_hash, family_id, username_lower, created_at, expires_at, used)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![token.token_hash, token.family_id, token.username.to_lowercase(), token.created_at, token.expires_at, token.used],
+    )
+}
+
+impl UserStore for SqliteStore {
+    fn create_user(&self, user_data: FullUserData) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = execute_user(
+            &conn,
+            "INSERT INTO users (username_lower, username, guid, email, avatar, password, pending_email_change, email_verified,
+                verification_sent_at, oauth_consents, two_factor, passkeys, magic_link_id,
+                failed_login_count, locked_until, disabled, roles, permissions, created_at, updated_at, last_login_at, last_login_ip, password_changed_at, deletion_scheduled_at, login_challenge)
+            VALUES (:username_lower, :username, :guid, :email, :avatar, :password, :pending_email_change, :email_verified,
+                :verification_sent_at, :oauth_consents, :two_factor, :passkeys, :magic_link_id,
+                :failed_login_count, :locked_until, :disabled, :roles, :permissions, :created_at, :updated_at, :last_login_at, :last_login_ip, :password_changed_at, :deletion_scheduled_at, :login_challenge)",
+            &user_data,
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to create user", err)),
+        }
+    }
+
+    fn write_user_data(&self, user_data: FullUserData) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = execute_user(
+            &conn,
+            "UPDATE users SET username = :username, guid = :guid, email = :email, avatar = :avatar, password = :password,
+            pending_email_change = :pending_email_change, email_verified = :email_verified,
+            verification_sent_at = :verification_sent_at, oauth_consents = :oauth_consents, two_factor = :two_factor,
+            passkeys = :passkeys, magic_link_id = :magic_link_id,
+            failed_login_count = :failed_login_count, locked_until = :locked_until, disabled = :disabled,
+            roles = :roles, permissions = :permissions,
+            created_at = :created_at,
+            updated_at = :updated_at, last_login_at = :last_login_at, last_login_ip = :last_login_ip,
+            password_changed_at = :password_changed_at, deletion_scheduled_at = :deletion_scheduled_at,
+            login_challenge = :login_challenge
+            WHERE username_lower = :username_lower",
+            &user_data,
+        );
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to save user data", err)),
+        }
+    }
+
+    fn read_user_data(&self, username: &str) -> Result<FullUserData, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let user_data = conn
+            .query_row("SELECT * FROM users WHERE username_lower = ?1", [username.to_lowercase()], row_to_user)
+            .optional();
+
+        match user_data {
+            Ok(Some(user_data)) => Ok(user_data),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read user data", err)),
+        }
+    }
+
+    fn rename_user(&self, username: &str, new_username: &str) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let result = (|| {
+            let tx = conn.transaction()?;
+            // Child rows still point at the old name until the end of the transaction
+            tx.pragma_update(None, "defer_foreign_keys", true)?;
+            let updated = tx.execute(
+                "UPDATE users SET username_lower = ?2, username = ?3 WHERE username_lower = ?1",
+                params![username.to_lowercase(), new_username.to_lowercase(), new_username],
+            )?;
+            tx.execute("UPDATE sessions SET username_lower = ?2 WHERE username_lower = ?1", params![username.to_lowercase(), new_username.to_lowercase()])?;
+            tx.execute("UPDATE otps SET username_lower = ?2 WHERE username_lower = ?1", params![username.to_lowercase(), new_username.to_lowercase()])?;
+            tx.execute("UPDATE refresh_tokens SET username_lower = ?2 WHERE username_lower = ?1", params![username.to_lowercase(), new_username.to_lowercase()])?;
+            tx.execute("UPDATE oauth_clients SET owner = ?2 WHERE owner = ?1", params![username.to_lowercase(), new_username.to_lowercase()])?;
+            tx.execute("UPDATE auth_codes SET username_lower = ?2 WHERE username_lower = ?1", params![username.to_lowercase(), new_username.to_lowercase()])?;
+            tx.commit()?;
+            Ok(updated)
+        })();
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to rename user", err)),
+        }
+    }
+
+    fn change_email(&self, username: &str, email: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "UPDATE users SET email = ?2, pending_email_change = NULL, email_verified = 1 WHERE username_lower = ?1",
+            params![username.to_lowercase(), email],
+        );
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to change email", err)),
+        }
+    }
+
+    fn list_users(&self, search: Option<&str>, offset: usize, limit: usize) -> Result<(Vec<FullUserData>, usize), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let search = search.map(|search| search.to_lowercase());
+        let result = (|| {
+            let filter = "?1 IS NULL OR instr(username_lower, ?1) > 0 OR instr(lower(email), ?1) > 0";
+            let total: i64 = conn.query_row(&format!("SELECT COUNT(*) FROM users WHERE {}", filter), [&search], |row| row.get(0))?;
+
+            let mut statement = conn.prepare(&format!("SELECT * FROM users WHERE {} ORDER BY username_lower LIMIT ?2 OFFSET ?3", filter))?;
+            let users = statement
+                .query_map(params![search, limit as i64, offset as i64], row_to_user)?
+                .collect::<rusqlite::Result<Vec<FullUserData>>>()?;
+            Ok((users, total as usize))
+        })();
+
+        match result {
+            Ok(page) => Ok(page),
+            Err(err) => Err(store_error("Failed to list users", err)),
+        }
+    }
+
+    fn delete_user(&self, username: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        // Sessions, otps, refresh tokens, OAuth clients and codes go with it through ON DELETE CASCADE
+        let result = conn.execute("DELETE FROM users WHERE username_lower = ?1", [username.to_lowercase()]);
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to delete user", err)),
+        }
+    }
+
+    fn deletions_due(&self, now: &str) -> Result<Vec<FullUserData>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = (|| {
+            let mut statement = conn.prepare("SELECT * FROM users WHERE deletion_scheduled_at <= ?1")?;
+            let users = statement.query_map([now], row_to_user)?.collect::<rusqlite::Result<Vec<FullUserData>>>()?;
+            Ok(users)
+        })();
+
+        match result {
+            Ok(users) => Ok(users),
+            Err(err) => Err(store_error("Failed to list accounts due for deletion", err)),
+        }
+    }
+
+    fn create_session(&self, username: &str, session: SessionData) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "INSERT INTO sessions (id, username_lower, session_key, created_at, last_seen_at, expires_at, device, ip)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                session.id,
+                username.to_lowercase(),
+                session.session_key,
+                session.created_at,
+                session.last_seen_at,
+                session.expires_at,
+                session.device,
+                session.ip,
+            ],
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to save session", err)),
+        }
+    }
+
+    fn touch_session(&self, username: &str, session_id: &str, last_seen_at: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "UPDATE sessions SET last_seen_at = ?3 WHERE username_lower = ?1 AND id = ?2",
+            params![username.to_lowercase(), session_id, last_seen_at],
+        );
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to update session", err)),
+        }
+    }
+
+    fn delete_session(&self, username: &str, session_id: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute("DELETE FROM sessions WHERE username_lower = ?1 AND id = ?2", params![username.to_lowercase(), session_id]);
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to delete session", err)),
+        }
+    }
+
+    fn delete_sessions_for_user(&self, username: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute("DELETE FROM sessions WHERE username_lower = ?1", [username.to_lowercase()]);
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to delete sessions", err)),
+        }
+    }
+
+    fn delete_inactive_sessions(&self, username: &str, seen_before: &str, now: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "DELETE FROM sessions WHERE username_lower = ?1 AND (last_seen_at < ?2 OR expires_at <= ?3)",
+            params![username.to_lowercase(), seen_before, now],
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to delete inactive sessions", err)),
+        }
+    }
+
+    fn read_sessions(&self, username: &str) -> Result<Vec<SessionData>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = (|| {
+            let mut statement = conn.prepare("SELECT * FROM sessions WHERE username_lower = ?1 ORDER BY created_at")?;
+            let rows = statement.query_map([username.to_lowercase()], |row| {
+                Ok(SessionData {
+                    id: row.get("id")?,
+                    session_key: row.get("session_key")?,
+                    created_at: row.get("created_at")?,
+                    last_seen_at: row.get("last_seen_at")?,
+                    expires_at: row.get("expires_at")?,
+                    device: row.get("device")?,
+                    ip: row.get("ip")?,
+                })
+            })?;
+            rows.collect::<rusqlite::Result<Vec<SessionData>>>()
+        })();
+
+        match result {
+            Ok(sessions) => Ok(sessions),
+            Err(err) => Err(store_error("Failed to read session data", err)),
+        }
+    }
+
+    fn passkey_owner(&self, credential_id: &str) -> Result<String, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let owner = conn
+            .query_row(
+                "SELECT username_lower FROM users, json_each(users.passkeys) WHERE json_extract(json_each.value, '$.id') = ?1",
+                [credential_id],
+                |row| row.get(0),
+            )
+            .optional();
+
+        match owner {
+            Ok(Some(owner)) => Ok(owner),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read passkeys", err)),
+        }
+    }
+
+    fn session_owner(&self, session_key: &str) -> Result<String, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let owner = conn
+            .query_row("SELECT username_lower FROM sessions WHERE session_key = ?1", [session_key], |row| row.get(0))
+            .optional();
+
+        match owner {
+            Ok(Some(owner)) => Ok(owner),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read session data", err)),
+        }
+    }
+
+    fn create_refresh_token(&self, token: RefreshTokenData) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = (|| {
+            // Expired tokens are dropped whenever a new one is added
+            conn.execute("DELETE FROM refresh_tokens WHERE expires_at < ?1", [timestamp(Utc::now())])?;
+            insert_refresh_token(&conn, &token)
+        })();
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to save refresh token", err)),
+        }
+    }
+
+    fn read_refresh_token(&self, token_hash: &str) -> Result<RefreshTokenData, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let token = conn
+            .query_row("SELECT * FROM refresh_tokens WHERE token_hash = ?1", [token_hash], |row| {
+                Ok(RefreshTokenData {
+                    token_hash: row.get("token_hash")?,
+                    family_id: row.get("family_id")?,
+                    username: row.get("username_lower")?,
+                    created_at: row.get("created_at")?,
+                    expires_at: row.get("expires_at")?,
+                    used: row.get("used")?,
+                })
+            })
+            .optional();
+
+        match token {
+            Ok(Some(token)) => Ok(token),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read refresh token", err)),
+        }
+    }
+
+    fn rotate_refresh_token(&self, token_hash: &str, replacement: RefreshTokenData) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let result = (|| {
+            let tx = conn.transaction()?;
+            let updated = tx.execute("UPDATE refresh_tokens SET used = 1 WHERE token_hash = ?1 AND used = 0", [token_hash])?;
+            if updated == 1 {
+                insert_refresh_token(&tx, &replacement)?;
+                tx.commit()?;
+            }
+            Ok(updated)
+        })();
+
+        match result {
+            Ok(1) => Ok(()),
+            Ok(_) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to rotate refresh token", err)),
+        }
+    }
+
+    fn revoke_refresh_tokens(&self, username: &str, family_id: Option<&str>) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = match family_id {
+            Some(family_id) => conn.execute(
+                "DELETE FROM refresh_tokens WHERE username_lower = ?1 AND family_id = ?2",
+                params![username.to_lowercase(), family_id],
+            ),
+            None => conn.execute("DELETE FROM refresh_tokens WHERE username_lower = ?1", [username.to_lowercase()]),
+        };
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to revoke refresh tokens", err)),
+        }
+    }
+
+    fn create_oauth_client(&self, client: OAuthClient) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "INSERT INTO oauth_clients (client_id, name, secret_hash, redirect_uris, owner, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                client.client_id,
+                client.name,
+                client.secret_hash,
+                serde_json::to_string(&client.redirect_uris).unwrap_or_default(),
+                client.owner.to_lowercase(),
+                client.created_at,
+            ],
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to save OAuth client", err)),
+        }
+    }
+
+    fn read_oauth_client(&self, client_id: &str) -> Result<OAuthClient, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let client = conn
+            .query_row("SELECT * FROM oauth_clients WHERE client_id = ?1", [client_id], |row| {
+                Ok(OAuthClient {
+                    client_id: row.get("client_id")?,
+                    name: row.get("name")?,
+                    secret_hash: row.get("secret_hash")?,
+                    redirect_uris: from_json_column(row, "redirect_uris")?.unwrap_or_default(),
+                    owner: row.get("owner")?,
+                    created_at: row.get("created_at")?,
+                })
+            })
+            .optional();
+
+        match client {
+            Ok(Some(client)) => Ok(client),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read OAuth client", err)),
+        }
+    }
+
+    fn create_auth_code(&self, code: AuthCodeData) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = (|| {
+            conn.execute("DELETE FROM auth_codes WHERE expires_at < ?1", [timestamp(Utc::now())])?;
+            conn.execute(
+                "INSERT INTO auth_codes (code_hash, client_id, username_lower, redirect_uri, scope, code_challenge, nonce, expires_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    code.code_hash,
+                    code.client_id,
+                    code.username.to_lowercase(),
+                    code.redirect_uri,
+                    code.scope,
+                    code.code_challenge,
+                    code.nonce,
+                    code.expires_at,
+                ],
+            )
+        })();
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to save authorization code", err)),
+        }
+    }
+
+    fn take_auth_code(&self, code_hash: &str) -> Result<AuthCodeData, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let code = conn
+            .query_row("DELETE FROM auth_codes WHERE code_hash = ?1 RETURNING *", [code_hash], |row| {
+                Ok(AuthCodeData {
+                    code_hash: row.get("code_hash")?,
+                    client_id: row.get("client_id")?,
+                    username: row.get("username_lower")?,
+                    redirect_uri: row.get("redirect_uri")?,
+                    scope: row.get("scope")?,
+                    code_challenge: row.get("code_challenge")?,
+                    nonce: row.get("nonce")?,
+                    expires_at: row.get("expires_at")?,
+                })
+            })
+            .optional();
+
+        match code {
+            Ok(Some(code)) => Ok(code),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read authorization code", err)),
+        }
+    }
+
+    fn write_otp_data(&self, otp_data: OTPData, username: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let result = conn.execute(
+            "INSERT OR REPLACE INTO otps (username_lower, otp_hash, date, attempts) VALUES (?1, ?2, ?3, ?4)",
+            params![username.to_lowercase(), otp_data.otp_hash, otp_data.date, otp_data.attempts],
+        );
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to save otp data", err)),
+        }
+    }
+
+    fn read_otp_data(&self, username: &str) -> Result<OTPData, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let otp_data = conn
+            .query_row(
+                "SELECT otp_hash, date, attempts FROM otps WHERE username_lower = ?1",
+                [username.to_lowercase()],
+                |row| Ok(OTPData { otp_hash: row.get(0)?, date: row.get(1)?, attempts: row.get(2)? }),
+            )
+            .optional();
+
+        match otp_data {
+            Ok(Some(otp_data)) => Ok(otp_data),
+            Ok(None) => Err(StoreError::NotFound),
+            Err(err) => Err(store_error("Failed to read otp data", err)),
+        }
+    }
+
+    fn delete_otp_data(&self, username: &str) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        match conn.execute("DELETE FROM otps WHERE username_lower = ?1", [username.to_lowercase()]) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(store_error("Failed to delete otp data", err)),
+        }
+    }
+
+    fn read_usermap(&self) -> Result<HashMap<String, String>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = match conn.prepare("SELECT lower(email), username_lower FROM users WHERE email IS NOT NULL") {
+            Ok(statement) => statement,
+            Err(err) => return Err(store_error("Failed to read usermap", err)),
+        };
+
+        let rows = match statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(rows) => rows,
+            Err(err) => return Err(store_error("Failed to read usermap", err)),
+        };
+
+        match rows.collect::<rusqlite::Result<HashMap<String, String>>>() {
+            Ok(user_map) => Ok(user_map),
+            Err(err) => Err(store_error("Failed to read usermap", err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_timestamp;
+
+    fn test_user(username: &str, email: &str) -> FullUserData {
+        serde_json::from_value(serde_json::json!({
+            "username": username,
+            "guid": 7,
+            "email": email,
+            "avatar": null,
+            "password": "hash",
+        }))
+        .unwrap()
+    }
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+    }
+
+    #[test]
+    fn migrates_an_empty_database_to_the_latest_version() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(user_version(&conn), 0);
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        // Nothing is left to apply on the next start
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrates_rows_written_by_older_schemas() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO users (username_lower, username, guid, email, avatar, password) VALUES ('alice', 'Alice', '7', 'a@x.io', NULL, 'hash');
+            INSERT INTO otps (username_lower, otp, date) VALUES ('alice', '123456', '2024-03-31 01:30:00');",
+        )
+        .unwrap();
+
+        // Up to the last version that saved local times
+        let utc_migration = MIGRATIONS.iter().position(|migration| migration.contains("strftime")).unwrap();
+        for migration in &MIGRATIONS[1..utc_migration] {
+            conn.execute_batch(migration).unwrap();
+        }
+        conn.pragma_update(None, "user_version", utc_migration).unwrap();
+        conn.execute("UPDATE users SET locked_until = '2024-03-31 01:30:00'", []).unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        let store = SqliteStore { conn: Mutex::new(conn) };
+        let user_data = store.read_user_data("ALICE").unwrap();
+        assert_eq!(user_data.username, "Alice");
+        assert_eq!(user_data.guid, 7);
+        assert!(!user_data.email_verified);
+        assert!(user_data.roles.is_empty());
+        assert_eq!(user_data.locked_until, parse_timestamp("2024-03-31 01:30:00").map(timestamp));
+
+        // Plain text codes are dropped rather than carried over
+        assert!(matches!(store.read_otp_data("alice"), Err(StoreError::NotFound)));
+    }
+
+    #[test]
+    fn stores_users_and_sessions_after_migrating() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.create_user(test_user("Bob", "Bob@x.io")).unwrap();
+        assert_eq!(store.read_user_data("bob").unwrap().email.as_deref(), Some("Bob@x.io"));
+        assert!(matches!(store.create_user(test_user("bob", "other@x.io")), Err(StoreError::UsernameTaken)));
+
+        let session = SessionData {
+            id: "session".to_string(),
+            session_key: "key".to_string(),
+            created_at: "2024-03-31T01:30:00Z".to_string(),
+            last_seen_at: "2024-03-31T01:30:00Z".to_string(),
+            expires_at: "2024-04-30T01:30:00Z".to_string(),
+            device: None,
+            ip: None,
+        };
+        store.create_session("bob", session).unwrap();
+        store.touch_session("bob", "session", "2024-03-31T02:00:00Z").unwrap();
+        assert_eq!(store.read_sessions("bob").unwrap()[0].last_seen_at, "2024-03-31T02:00:00Z");
+
+        store.delete_session("bob", "session").unwrap();
+        assert!(matches!(store.delete_session("bob", "session"), Err(StoreError::NotFound)));
+        assert!(matches!(store.touch_session("bob", "session", "2024-03-31T03:00:00Z"), Err(StoreError::NotFound)));
+    }
+
+    #[test]
+    fn refuses_rows_that_do_not_decode() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.create_user(test_user("Bob", "bob@x.io")).unwrap();
+        store.create_user(test_user("Carol", "carol@x.io")).unwrap();
+
+        let conn = store.conn.lock().unwrap();
+        conn.execute("UPDATE users SET two_factor = '{\"secret\": 1' WHERE username_lower = 'bob'", []).unwrap();
+        conn.execute("UPDATE users SET guid = 'not a guid' WHERE username_lower = 'carol'", []).unwrap();
+        drop(conn);
+
+        assert!(matches!(store.read_user_data("bob"), Err(StoreError::Io(_))));
+        assert!(matches!(store.read_user_data("carol"), Err(StoreError::Io(_))));
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+
+use crate::errors::StoreError;
+use crate::{AuthCodeData, FullUserData, OAuthClient, OTPData, RefreshTokenData, SessionData};
+
+/// Persistence for users, sessions, refresh tokens, OAuth clients and codes, OTPs
+/// and the email -> username index.
+///
+/// Handlers only talk to this trait, so the backend can be swapped at startup
+/// without touching request handling. Usernames are matched case-insensitively,
+/// and reading a user that doesn't exist fails with `StoreError::NotFound`.
+pub trait UserStore: Send + Sync {
+    /// Inserts a new account, failing if the username or email is already taken.
+    fn create_user(&self, user_data: FullUserData) -> Result<(), StoreError>;
+    fn write_user_data(&self, user_data: FullUserData) -> Result<(), StoreError>;
+    fn read_user_data(&self, username: &str) -> Result<FullUserData, StoreError>;
+    /// Moves an account and everything attached to it (sessions, otp, email index
+    /// entry) to a new username in one step, failing if the new name is taken.
+    fn rename_user(&self, username: &str, new_username: &str) -> Result<(), StoreError>;
+    /// Sets a confirmed email address, marking it verified and clearing any pending
+    /// change, and updates the email index in the same step. Fails if another
+    /// account uses the address.
+    fn change_email(&self, username: &str, email: &str) -> Result<(), StoreError>;
+    /// One page of accounts ordered by username, and how many accounts match in all.
+    /// `search` keeps the accounts whose username or email contains it, ignoring case.
+    fn list_users(&self, search: Option<&str>, offset: usize, limit: usize) -> Result<(Vec<FullUserData>, usize), StoreError>;
+    /// Removes an account and everything attached to it (sessions, otp, refresh
+    /// tokens, OAuth clients and codes, email index entry).
+    fn delete_user(&self, username: &str) -> Result<(), StoreError>;
+    /// Accounts whose scheduled deletion is at or before `now`.
+    fn deletions_due(&self, now: &str) -> Result<Vec<FullUserData>, StoreError>;
+
+    // Sessions change one at a time, so concurrent logins, logouts and revokes never
+    // write back a stale copy of the list
+    fn create_session(&self, username: &str, session: SessionData) -> Result<(), StoreError>;
+    /// Marks a session as seen; NotFound if it has been revoked in the meantime.
+    fn touch_session(&self, username: &str, session_id: &str, last_seen_at: &str) -> Result<(), StoreError>;
+    /// NotFound if the user has no session with this id.
+    fn delete_session(&self, username: &str, session_id: &str) -> Result<(), StoreError>;
+    fn delete_sessions_for_user(&self, username: &str) -> Result<(), StoreError>;
+    /// Drops sessions last seen before `seen_before` or expiring by `now`.
+    fn delete_inactive_sessions(&self, username: &str, seen_before: &str, now: &str) -> Result<(), StoreError>;
+    fn read_sessions(&self, username: &str) -> Result<Vec<SessionData>, StoreError>;
+    /// Username owning the session with this key, expired or not.
+    fn session_owner(&self, session_key: &str) -> Result<String, StoreError>;
+    /// Username of the account a passkey credential id is registered to.
+    fn passkey_owner(&self, credential_id: &str) -> Result<String, StoreError>;
+
+    fn create_refresh_token(&self, token: RefreshTokenData) -> Result<(), StoreError>;
+    fn read_refresh_token(&self, token_hash: &str) -> Result<RefreshTokenData, StoreError>;
+    /// Marks the token used and saves its replacement in one step. Fails with
+    /// `NotFound` if the token is missing or another request already used it.
+    fn rotate_refresh_token(&self, token_hash: &str, replacement: RefreshTokenData) -> Result<(), StoreError>;
+    /// Deletes one family of the user's refresh tokens, or all of them when `family_id` is None.
+    fn revoke_refresh_tokens(&self, username: &str, family_id: Option<&str>) -> Result<(), StoreError>;
+
+    fn create_oauth_client(&self, client: OAuthClient) -> Result<(), StoreError>;
+    fn read_oauth_client(&self, client_id: &str) -> Result<OAuthClient, StoreError>;
+    fn create_auth_code(&self, code: AuthCodeData) -> Result<(), StoreError>;
+    /// Removes and returns the code, so each one can only be exchanged once.
+    fn take_auth_code(&self, code_hash: &str) -> Result<AuthCodeData, StoreError>;
+
+    fn write_otp_data(&self, otp_data: OTPData, username: &str) -> Result<(), StoreError>;
+    fn read_otp_data(&self, username: &str) -> Result<OTPData, StoreError>;
+    /// Removes the user's reset code, if there is one.
+    fn delete_otp_data(&self, username: &str) -> Result<(), StoreError>;
+
+    /// Lowercased email -> lowercased username for every account with an email.
+    fn read_usermap(&self) -> Result<HashMap<String, String>, StoreError>;
+}
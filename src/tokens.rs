@@ -0,0 +1,173 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{Duration, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::path::Path;
+
+use crate::utils::write_secret_file;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Deserialize, Serialize)]
+struct SignedPayload<T> {
+    purpose: String,
+    exp: i64,
+    claims: T,
+}
+
+/// Issues and checks HMAC signed, expiring tokens of the form `payload.signature`,
+/// both base64url encoded. The purpose is signed too so a token issued for one
+/// flow can't be replayed against another.
+pub struct TokenSigner {
+    secret: Vec<u8>,
+}
+
+impl TokenSigner {
+    /// Reads the base64 encoded secret from `path`, creating a random one on first start.
+    pub fn load_or_create(path: &Path) -> std::io::Result<TokenSigner> {
+        if let Ok(secret) = fs::read_to_string(path) {
+            return match URL_SAFE_NO_PAD.decode(secret.trim()) {
+                Ok(secret) if secret.len() >= 32 => Ok(TokenSigner { secret }),
+                _ => Err(std::io::Error::other(format!("Invalid token secret in {}", path.display()))),
+            };
+        }
+
+        let mut secret = vec![0u8; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        write_secret_file(path, &URL_SAFE_NO_PAD.encode(&secret))?;
+        Ok(TokenSigner { secret })
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length")
+    }
+
+    /// Keyed hash for short secrets like reset codes, which a plain hash would not
+    /// protect if the store leaked.
+    pub fn hash(&self, value: &str) -> String {
+        let mut mac = self.mac();
+        mac.update(value.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    pub fn sign<T: Serialize>(&self, purpose: &str, claims: &T, lifetime: Duration) -> String {
+        let payload = SignedPayload { purpose: purpose.to_string(), exp: (Utc::now() + lifetime).timestamp(), claims };
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&payload).unwrap_or_default());
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Returns the claims if the signature is valid, the purpose matches and the token has not expired.
+    pub fn verify<T: DeserializeOwned>(&self, purpose: &str, token: &str) -> Option<T> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).ok()?;
+
+        let payload: SignedPayload<T> = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        if payload.purpose != purpose || payload.exp < Utc::now().timestamp() {
+            return None;
+        }
+
+        Some(payload.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Claims {
+        username: String,
+    }
+
+    fn signer(byte: u8) -> TokenSigner {
+        TokenSigner { secret: vec![byte; 32] }
+    }
+
+    fn claims() -> Claims {
+        Claims { username: "alice".to_string() }
+    }
+
+    #[test]
+    fn verifies_its_own_tokens() {
+        let tokens = signer(1);
+        let token = tokens.sign("reset", &claims(), Duration::minutes(5));
+        assert_eq!(tokens.verify::<Claims>("reset", &token), Some(claims()));
+    }
+
+    #[test]
+    fn binds_tokens_to_their_purpose() {
+        let tokens = signer(1);
+        let token = tokens.sign("reset", &claims(), Duration::minutes(5));
+        assert_eq!(tokens.verify::<Claims>("magic_link", &token), None);
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let tokens = signer(1);
+        let token = tokens.sign("reset", &claims(), Duration::seconds(-10));
+        assert_eq!(tokens.verify::<Claims>("reset", &token), None);
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_another_secret() {
+        let token = signer(2).sign("reset", &claims(), Duration::minutes(5));
+        assert_eq!(signer(1).verify::<Claims>("reset", &token), None);
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let tokens = signer(1);
+        let token = tokens.sign("reset", &claims(), Duration::minutes(5));
+        let (_, signature) = token.split_once('.').unwrap();
+
+        // Someone else's claims under the original signature
+        let forged = SignedPayload { purpose: "reset".to_string(), exp: Utc::now().timestamp() + 300, claims: Claims { username: "bob".to_string() } };
+        let forged = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+        assert_eq!(tokens.verify::<Claims>("reset", &format!("{}.{}", forged, signature)), None);
+
+        assert_eq!(tokens.verify::<Claims>("reset", &format!("{}x", token)), None);
+        assert_eq!(tokens.verify::<Claims>("reset", "not a token"), None);
+    }
+
+    #[test]
+    fn hashes_are_keyed() {
+        assert_eq!(signer(1).hash("123456"), signer(1).hash("123456"));
+        assert_ne!(signer(1).hash("123456"), signer(1).hash("654321"));
+        assert_ne!(signer(1).hash("123456"), signer(2).hash("123456"));
+    }
+
+    #[test]
+    fn keeps_the_secret_it_creates() {
+        let path = std::env::temp_dir().join(format!("token_secret_{}", rand::random::<u64>()));
+        let created = TokenSigner::load_or_create(&path).unwrap();
+        let loaded = TokenSigner::load_or_create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let token = created.sign("reset", &claims(), Duration::minutes(5));
+        assert_eq!(loaded.verify::<Claims>("reset", &token), Some(claims()));
+    }
+
+    #[test]
+    fn refuses_short_secrets() {
+        let path = std::env::temp_dir().join(format!("token_secret_{}", rand::random::<u64>()));
+        fs::write(&path, URL_SAFE_NO_PAD.encode([0u8; 16])).unwrap();
+        let result = TokenSigner::load_or_create(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert!(result.is_err());
+    }
+}
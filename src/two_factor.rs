@@ -0,0 +1,272 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use rand::RngCore;
+use totp_rs::{Algorithm, Secret, TOTP};
+use warp::{Filter, Rejection, Reply};
+
+use crate::audit::{client_info, ClientInfo};
+use crate::auth::{check_not_locked, with_auth, AuthContext};
+use crate::errors::{ApiError, StoreError};
+use crate::password::PasswordCheck;
+use crate::rate_limit::{count_failed_login, limit_ip};
+use crate::utils::{constant_time_eq, count_wrong_guess, hash_token, random_string};
+use crate::*;
+
+/// Signed into login challenge tokens so no other kind of token is accepted in their place
+pub const CHALLENGE_PURPOSE: &str = "login_2fa";
+
+// RFC 6238 defaults, which every authenticator app supports
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+// Codes from one step either side are accepted to allow for clock drift
+const SKEW_STEPS: u64 = 1;
+
+pub fn routes(state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let setup = warp::path("2fa")
+        .and(warp::path("setup"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_setup);
+
+    let confirm = warp::path("2fa")
+        .and(warp::path("confirm"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_confirm);
+
+    let disable = warp::path("2fa")
+        .and(warp::path("disable"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_disable);
+
+    let recovery_codes = warp::path("2fa")
+        .and(warp::path("recovery_codes"))
+        .and(warp::post())
+        .and(with_auth(state.clone()))
+        .and(warp::body::json())
+        .and(with_state(state.clone()))
+        .and_then(handle_regenerate_recovery_codes);
+
+    let login = warp::path("2fa")
+        .and(warp::path("login"))
+        .and(warp::post())
+        .and(limit_ip(state.clone(), "2fa_login"))
+        .and(warp::body::json())
+        .and(client_info(state.clone()))
+        .and(with_state(state.clone()))
+        .and_then(handle_two_factor_login);
+
+    setup.or(confirm).or(disable).or(recovery_codes).or(login)
+}
+
+fn totp(state: &AppState, secret: &str, username: &str) -> Result<TOTP, Rejection> {
+    let secret = match Secret::Encoded(secret.to_string()).to_bytes() {
+        Ok(secret) => secret,
+        Err(err) => return Err(ApiError::Internal(format!("Invalid TOTP secret for {}: {:?}", username, err)).into()),
+    };
+
+    let issuer = Some(state.config.auth.two_factor.issuer.clone());
+    match TOTP::new(Algorithm::SHA1, DIGITS, SKEW_STEPS as u8, STEP_SECONDS, secret, issuer, username.to_string()) {
+        Ok(totp) => Ok(totp),
+        Err(err) => Err(ApiError::Internal(format!("Failed to set up TOTP for {}: {}", username, err)).into()),
+    }
+}
+
+// Accepts a code from the authenticator app, unless that step's code has already been used
+fn check_totp(totp: &TOTP, two_factor: &mut TwoFactorData, code: &str) -> bool {
+    let current_step = Utc::now().timestamp() as u64 / STEP_SECONDS;
+    for step in current_step.saturating_sub(SKEW_STEPS)..=current_step + SKEW_STEPS {
+        if step > two_factor.last_used_step && constant_time_eq(&totp.generate(step * STEP_SECONDS), code) {
+            two_factor.last_used_step = step;
+            return true;
+        }
+    }
+
+    false
+}
+
+// Recovery codes are shown grouped and in upper case, but accepted however they're typed
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_uppercase()
+}
+
+// Accepts a TOTP code or uses up a recovery code. The caller has to save the user
+// data afterwards so neither can be used again.
+pub fn check_code(state: &AppState, user_data: &mut FullUserData, code: &str) -> Result<bool, Rejection> {
+    let username = user_data.username.clone();
+    let two_factor = match user_data.two_factor.as_mut() {
+        Some(two_factor) if two_factor.enabled => two_factor,
+        _ => return Err(ApiError::TwoFactorNotEnabled.into()),
+    };
+
+    let code = code.trim();
+    if code.len() == DIGITS && code.chars().all(|c| c.is_ascii_digit()) {
+        let totp = totp(state, &two_factor.secret, &username)?;
+        return Ok(check_totp(&totp, two_factor, code));
+    }
+
+    let code_hash = hash_token(&normalize_recovery_code(code));
+    match two_factor.recovery_codes.iter().position(|recovery_code| constant_time_eq(recovery_code, &code_hash)) {
+        Some(index) => {
+            two_factor.recovery_codes.remove(index);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+// Returns the codes to show once, and their hashes to keep
+fn new_recovery_codes(count: i64) -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..count).map(|_| {
+        let code = random_string(10).to_uppercase();
+        format!("{}-{}", &code[..5], &code[5..])
+    }).collect();
+    let hashes = codes.iter().map(|code| hash_token(&normalize_recovery_code(code))).collect();
+
+    (codes, hashes)
+}
+
+async fn handle_setup(auth: AuthContext, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&auth.username)?;
+    if user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled) {
+        return Err(ApiError::TwoFactorEnabled.into());
+    }
+
+    // 160 bits, the key length RFC 4226 recommends for HMAC-SHA1
+    let mut secret = vec![0u8; 20];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = match Secret::Raw(secret).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => return Err(ApiError::Internal("Failed to encode TOTP secret".to_string()).into()),
+    };
+    let otpauth_uri = totp(&state, &secret, &user_data.username)?.get_url();
+
+    // Starting again replaces a secret that was never confirmed
+    user_data.two_factor = Some(TwoFactorData { secret: secret.clone(), enabled: false, recovery_codes: Vec::new(), last_used_step: 0 });
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&TwoFactorSetup { secret, otpauth_uri }))
+}
+
+async fn handle_confirm(auth: AuthContext, req: TwoFactorCode, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut user_data = state.store.read_user_data(&auth.username)?;
+    let username = user_data.username.clone();
+    let two_factor = match user_data.two_factor.as_mut() {
+        Some(two_factor) if two_factor.enabled => return Err(ApiError::TwoFactorEnabled.into()),
+        Some(two_factor) => two_factor,
+        None => return Err(ApiError::TwoFactorNotEnabled.into()),
+    };
+
+    let totp = totp(&state, &two_factor.secret, &username)?;
+    if !check_totp(&totp, two_factor, req.code.trim()) {
+        return Err(ApiError::InvalidCode.into());
+    }
+
+    let (recovery_codes, recovery_code_hashes) = new_recovery_codes(state.config.auth.two_factor.recovery_codes);
+    two_factor.enabled = true;
+    two_factor.recovery_codes = recovery_code_hashes;
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&RecoveryCodes { recovery_codes }))
+}
+
+// Checks the password and a second factor again before two-factor settings change.
+// Wrong answers count toward the lockout like they do at login, so a stolen session
+// can't be used to guess the password.
+async fn reauthenticate(state: &AppState, mut user_data: FullUserData, req: &TwoFactorReauth) -> Result<FullUserData, Rejection> {
+    if !user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled) {
+        return Err(ApiError::TwoFactorNotEnabled.into());
+    }
+    check_not_locked(&user_data)?;
+
+    let error = if matches!(state.passwords.verify(&req.password, &user_data.password).await, PasswordCheck::Invalid) {
+        ApiError::InvalidCredentials
+    } else if !check_code(state, &mut user_data, &req.code)? {
+        ApiError::InvalidCode
+    } else {
+        return Ok(user_data);
+    };
+
+    count_failed_login(&state.config.rate_limit, &mut user_data);
+    state.store.write_user_data(user_data)?;
+    Err(error.into())
+}
+
+async fn handle_disable(auth: AuthContext, req: TwoFactorReauth, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let mut user_data = reauthenticate(&state, user_data, &req).await?;
+
+    user_data.two_factor = None;
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&"Two-factor authentication disabled"))
+}
+
+async fn handle_regenerate_recovery_codes(auth: AuthContext, req: TwoFactorReauth, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user_data = state.store.read_user_data(&auth.username)?;
+    let mut user_data = reauthenticate(&state, user_data, &req).await?;
+
+    let (recovery_codes, recovery_code_hashes) = new_recovery_codes(state.config.auth.two_factor.recovery_codes);
+    if let Some(two_factor) = user_data.two_factor.as_mut() {
+        two_factor.recovery_codes = recovery_code_hashes;
+    }
+    state.store.write_user_data(user_data)?;
+
+    Ok(warp::reply::json(&RecoveryCodes { recovery_codes }))
+}
+
+async fn handle_two_factor_login(req: TwoFactorLogin, client: ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let guid = state.tokens.verify::<TwoFactorChallengeClaims>(CHALLENGE_PURPOSE, &req.challenge_token).map(|claims| claims.guid);
+    let result = two_factor_login(req, &client, state.clone()).await;
+    // Successes are recorded by start_session
+    if result.is_err() {
+        state.audit.record(AuditEvent::Login, guid, &client, &result, None);
+    }
+    result
+}
+
+async fn two_factor_login(req: TwoFactorLogin, client: &ClientInfo, state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let claims: TwoFactorChallengeClaims = match state.tokens.verify(CHALLENGE_PURPOSE, &req.challenge_token) {
+        Some(claims) => claims,
+        None => return Err(ApiError::InvalidToken.into()),
+    };
+
+    // The challenge is void if the account was replaced or two-factor was turned off since
+    let mut user_data = match state.store.read_user_data(&claims.username) {
+        Ok(user_data) if user_data.guid == claims.guid && user_data.two_factor.as_ref().is_some_and(|two_factor| two_factor.enabled) => user_data,
+        Ok(_) | Err(StoreError::NotFound) => return Err(ApiError::InvalidToken.into()),
+        Err(err) => return Err(err.into()),
+    };
+
+    // Only the latest challenge works, until it is spent
+    let mut failed_attempts = match &user_data.login_challenge {
+        Some(challenge) if challenge.id == claims.challenge_id => challenge.failed_attempts,
+        _ => return Err(ApiError::InvalidToken.into()),
+    };
+    check_not_locked(&user_data)?;
+
+    // Wrong codes count toward the same lockout as wrong passwords
+    if !check_code(&state, &mut user_data, &req.code)? {
+        user_data.login_challenge = if count_wrong_guess(&mut failed_attempts, state.config.auth.two_factor.challenge_max_attempts) {
+            Some(LoginChallenge { id: claims.challenge_id, failed_attempts })
+        } else {
+            None
+        };
+        count_failed_login(&state.config.rate_limit, &mut user_data);
+        state.store.write_user_data(user_data)?;
+        return Err(ApiError::InvalidCode.into());
+    }
+
+    let username = user_data.username.clone();
+    state.store.write_user_data(user_data)?;
+
+    let response = start_session(&state, &username, claims.guid, claims.device, client, "totp")?;
+    Ok(warp::reply::json(&response))
+}
@@ -1,242 +1,327 @@
-use reqwest::Client;
+use chrono::{DateTime, Local, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use crypto_hash::{hex_digest, Algorithm as HashAlgorithm};
+use rand::Rng;
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::path::Path;
 
-use crate::{EmailAddress, FullUserData, OTPData, Personalization, SendGridEmail, SessionData};
+use crate::errors::StoreError;
+use crate::mailer::{Email, EmailKind, Mailer};
+use crate::store::UserStore;
+use crate::tokens::TokenSigner;
 
-pub fn write_user_data(user_data: FullUserData) -> Result<(),()> {
-    let file_path = format!("./Json/Users/{}/user_data.txt", user_data.username.to_lowercase());
-    if !fs::metadata(format!("./Json/Users/{}/", & user_data.username.to_lowercase())).is_ok() {
-        let _  = fs::create_dir(format!("./Json/Users/{}", & user_data.username.to_lowercase()));
-    }
-
-    let serialized_user_data = match serde_json::to_string(&user_data){
-        Ok(user_data) => user_data,
-        Err(_) => return Err(()),
-    };
+pub async fn send_otp(mailer: &dyn Mailer, otp: &str, username: &str, email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::PasswordReset, email)
+        .with("username", username)
+        .with("otp", otp)
+        .with("email", email);
 
-    match fs::write(&file_path, serialized_user_data) {
-        Ok(_) => return Ok(()),
-        Err(_) => return Err(()),
-    }
+    mailer.send(&message).await
 }
 
-pub fn read_user_data(username: &str) -> Result<FullUserData,()> {
-    let file_path = format!("./Json/Users/{}/user_data.txt", username.to_lowercase());
-    if !fs::metadata(&file_path).is_ok() {
-        return Err(());
-    }
+// Sent to the new address, which has to be confirmed with the code before it replaces the old one
+pub async fn send_email_change_code(mailer: &dyn Mailer, code: &str, username: &str, new_email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::EmailChangeCode, new_email)
+        .with("username", username)
+        .with("code", code)
+        .with("email", new_email);
 
-    let user_str = match read_from_file(&file_path) {
-        Some(data) => data,
-        None => return Err(()),
-    };
+    mailer.send(&message).await
+}
 
-    match serde_json::from_str(&user_str){
-        Ok(user_data) => return Ok(user_data),
-        Err(_) => return Err(()),
-    };
+// Lets the owner of the old address know in case they did not ask for the change
+pub async fn send_email_change_notice(mailer: &dyn Mailer, username: &str, old_email: &str, new_email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::EmailChangeNotice, old_email)
+        .with("username", username)
+        .with("email", old_email)
+        .with("new_email", new_email);
+
+    mailer.send(&message).await
 }
 
-pub fn write_sesion_data(session_data: SessionData, username: &String) -> Result<(),()> {
-    let file_path = format!("./Json/Users/{}/session_data.txt", username.to_lowercase());
-    if !fs::metadata(format!("./Json/Users/{}/", & username.to_lowercase())).is_ok() {
-        return Err(());
-    }
+pub async fn send_email_verification(mailer: &dyn Mailer, link: &str, username: &str, email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::EmailVerification, email)
+        .with("username", username)
+        .with("link", link)
+        .with("email", email);
 
-    let serialized_session_data = match serde_json::to_string(&session_data){
-        Ok(session_data) => session_data,
-        Err(_) => return Err(()),
-    };
+    mailer.send(&message).await
+}
 
-    match fs::write(&file_path, serialized_session_data) {
-        Ok(_) => return Ok(()),
-        Err(_) => return Err(()),
-    }
+pub async fn send_magic_link(mailer: &dyn Mailer, link: &str, username: &str, email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::MagicLink, email)
+        .with("username", username)
+        .with("link", link)
+        .with("email", email);
+
+    mailer.send(&message).await
 }
 
-pub fn read_session_data(username: &str) -> Result<SessionData,()> {
-    let file_path = format!("./Json/Users/{}/session_data.txt", username.to_lowercase());
-    if !fs::metadata(&file_path).is_ok() {
-        return Err(());
-    }
+pub async fn send_deletion_scheduled(mailer: &dyn Mailer, deletion_date: &str, username: &str, email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::AccountDeletionScheduled, email)
+        .with("username", username)
+        .with("deletion_date", deletion_date);
+
+    mailer.send(&message).await
+}
 
-    let session_str = match read_from_file(&file_path) {
-        Some(data) => data,
-        None => return Err(()),
-    };
+pub async fn send_account_deleted(mailer: &dyn Mailer, username: &str, email: &str) -> Result<(), String> {
+    let message = Email::new(EmailKind::AccountDeleted, email)
+        .with("username", username);
 
-    match serde_json::from_str(&session_str){
-        Ok(session_data) => return Ok(session_data),
-        Err(_) => return Err(()),
-    };
+    mailer.send(&message).await
 }
 
-pub fn write_otp_data(otp_data: OTPData, username: &String) -> Result<(),()> {
-    let file_path = format!("./Json/Users/{}/otp_data.txt", username.to_lowercase());
-    if !fs::metadata(format!("./Json/Users/{}/", & username.to_lowercase())).is_ok() {
-        return Err(());
+// Resolves a login that may be an email address to a username
+pub fn email_lookup(store: &dyn UserStore, login: &str) -> Result<String, StoreError> {
+    if login.contains('@'){
+        let user_map: HashMap<String, String> = store.read_usermap()?;
+
+        // Retrieve the username based on an email
+        match user_map.get(&login.to_lowercase()) {
+            Some(username) => Ok(username.clone()),
+            None => Err(StoreError::NotFound),
+        }
+    } else {
+        Ok(login.to_string())
     }
+}
+
+// Stored times are UTC in RFC 3339 with whole seconds, e.g. 2024-03-31T01:30:00Z. The
+// fixed width means they also sort and compare correctly as plain strings.
+pub fn timestamp(datetime: DateTime<Utc>) -> String {
+    datetime.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
 
-    let serialized_otp_data = match serde_json::to_string(&otp_data){
-        Ok(otp_data) => otp_data,
-        Err(_) => return Err(()),
-    };
+// Also reads the "%Y-%m-%d %H:%M:%S" server local times records used to be saved with,
+// so they keep working until they are next written.
+pub fn parse_timestamp(timestamp: &str) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(timestamp) {
+        return Some(datetime.with_timezone(&Utc));
+    }
 
-    match fs::write(&file_path, serialized_otp_data) {
-        Ok(_) => return Ok(()),
-        Err(_) => return Err(()),
+    match NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S") {
+        Ok(datetime) => Local.from_local_datetime(&datetime).earliest().map(|datetime| datetime.with_timezone(&Utc)),
+        Err(_) => None,
     }
 }
 
-pub fn read_otp_data(username: &str) -> Result<OTPData,()> {
-    let file_path = format!("./Json/Users/{}/otp_data.txt", username.to_lowercase());
-    if !fs::metadata(&file_path).is_ok() {
-        return Err(());
+pub fn random_string(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+    let characters = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+    (0..length).map(|_| {
+            let index = rng.gen_range(0..characters.len());
+            characters.chars().nth(index).unwrap()
+        })
+        .collect()
+}
+
+// Long random tokens don't need a slow hash, this only keeps them unusable if the store leaks
+pub fn hash_token(token: &str) -> String {
+    hex_digest(HashAlgorithm::SHA256, token.as_bytes())
+}
+
+pub fn valid_email(email: &str) -> bool {
+    match email.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && !email.chars().any(char::is_whitespace)
+        }
+        None => false,
     }
+}
 
-    let otp_str = match read_from_file(&file_path) {
-        Some(data) => data,
-        None => return Err(()),
-    };
+pub fn random_code(length: usize, alphabet: &str) -> String {
+    let alphabet: Vec<char> = alphabet.chars().collect();
+    let mut rng = rand::thread_rng();
+    (0..length).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect()
+}
 
-    match serde_json::from_str(&otp_str){
-        Ok(otp_data) => return Ok(otp_data),
-        Err(_) => return Err(()),
-    };
+// Takes the same time wherever the first difference is, so a match can't be found
+// one character at a time by timing responses
+pub fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
 }
 
-pub async fn send_otp(otp: &str, username: &str, email: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let api_key = "API_KEY";
-    let sender_email = "no-reply@gmail.com";
-    let recipient_email = &email.to_string();
-    let template_id = "d-36dab063ce184e4180e716439b12ac9a";
+// Codes are stored keyed-hashed and may be typed with stray whitespace around them
+pub fn code_matches(tokens: &TokenSigner, code: &str, code_hash: &str) -> bool {
+    constant_time_eq(&tokens.hash(code.trim()), code_hash)
+}
 
-    let url = "https://api.sendgrid.com/v3/mail/send";
+// Counts a wrong guess at a code, false once it has run out of attempts
+pub fn count_wrong_guess(attempts: &mut u32, max_attempts: u32) -> bool {
+    *attempts = attempts.saturating_add(1);
+    *attempts < max_attempts
+}
 
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        reqwest::header::AUTHORIZATION,
-        format!("Bearer {}", api_key).parse().unwrap(),
-    );
-    headers.insert(
-        reqwest::header::CONTENT_TYPE,
-        reqwest::header::HeaderValue::from_static("application/json"),
-    );
+pub fn random_digits(length: usize) -> String {
+    (0..length)
+        .map(|_| rand::thread_rng().gen_range(0..=9).to_string())
+        .collect()
+}
 
-    let mut dynamic_template_data = HashMap::new();
-    dynamic_template_data.insert("username".to_string(), username.to_string());
-    dynamic_template_data.insert("otp".to_string(), otp.to_string());
-    dynamic_template_data.insert("email".to_string(), email.to_string());
+// Usernames double as directory names and must not look like an email address
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= 32
+        && username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+        && username.chars().any(|c| c.is_ascii_alphanumeric())
+}
 
-    let email = SendGridEmail {
-        personalizations: vec![Personalization {
-            to: vec![EmailAddress {
-                email: recipient_email.to_string(),
-            }],
-            dynamic_template_data,
-        }],
-        from: EmailAddress {
-            email: sender_email.to_string(),
-        },
-        template_id: template_id.to_string(),
-    };
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
 
-    let client = Client::new();
-    let response = client
-        .post(url)
-        .headers(headers)
-        .body(serde_json::to_string(&email)?)
-        .send()
-        .await;
-
-    match response {
-        Ok(response) => {
-            println!("Response: {:?}", response);
-            Ok(())
-        }
-        Err(err) => {
-            eprintln!("Error sending email: {}", err);
-            Err(Box::new(err))
-        }
-    }
+// The avatar is stored as given, usually an image URL
+pub fn valid_avatar(avatar: &str) -> bool {
+    avatar.len() <= 2048 && !avatar.chars().any(char::is_control)
 }
 
-pub fn email_lookup(login: &str) -> Result<String, Box<dyn std::error::Error>> {
-    if login.contains("@"){
-        // Read the user map file and create a HashMap of username-email mappings
-        let user_map: HashMap<String, String> = match read_usermap(){
-            Ok(user_map) => user_map,
-            Err(_) =>return Err("Unable to read user map.".into()),
-        };
+// Writes to a temporary file first so readers never see a half written file
+pub fn write_file_atomic(path: &Path, data: &str) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)
+}
 
-        // Retrieve the username based on an email
-        if let Some(username) = user_map.get(&login.to_lowercase()) {
-            Ok(username.clone())
-        } else {
-            Err("Email not found in the user map.".into())
-        }
-    } else {
-        Ok(login.to_string())
+// Like write_file_atomic, for keys: the file is only ever readable by the server's user
+pub fn write_secret_file(path: &Path, data: &str) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    // A leftover temporary file would keep whatever permissions it was created with
+    let _ = fs::remove_file(&tmp_path);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
     }
+
+    let mut file = options.open(&tmp_path)?;
+    file.write_all(data.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
 }
 
-pub fn read_usermap() -> Result<HashMap<String, String>, String> {
-    let target_directory = Path::new("./Json/");
-    let user_map_file_path = target_directory.join("user_map.txt");
-    match read_from_file(&user_map_file_path.to_string_lossy().to_string()) {
-        Some(hash_map_str) => {
-            let parsed_data: Result<HashMap<String, String>, serde_json::Error> =
-                serde_json::from_str(&hash_map_str);
-            match parsed_data {
-                Ok(data) => {
-                    println!("Deserialized usermap");
-                    return Ok(data);
-                }
-                Err(_) => {
-                    return Err("Failed to deserialize usermap".to_string());
-                }
-            }
-        }
-        None => return Err("Could not find usermap".to_string()),
+pub fn read_from_file(relative_path: &str) -> Option<String> {
+    if fs::metadata(relative_path).is_err() {
+        return None;
     }
+
+    fs::read_to_string(relative_path).ok()
 }
 
-pub fn write_usermap(usermap: &HashMap<String, String>) -> Result<String, String> {
-    let target_directory = Path::new("./Json/");
-    let user_map_file_path = target_directory.join("user_map.txt");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match serde_json::to_string(&usermap) {
-        Ok(user_map_string) => write_to_file(&user_map_file_path.to_string_lossy().to_string(), &user_map_string),
-        Err(_) => return Err("Failed to save updated usermap".to_string()),
-    };
+    fn tokens() -> TokenSigner {
+        let path = std::env::temp_dir().join(format!("token_secret_{}", rand::random::<u64>()));
+        let tokens = TokenSigner::load_or_create(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        tokens
+    }
 
-    Ok("Saved updated user map".to_string())
-}
+    #[test]
+    fn random_codes_use_the_alphabet_given() {
+        let code = random_code(12, "ABC234");
+        assert_eq!(code.chars().count(), 12);
+        assert!(code.chars().all(|c| "ABC234".contains(c)));
+    }
 
-fn read_from_file(relative_path: &String) -> Option<String> {
-    if !fs::metadata(relative_path).is_ok() {
-        return None;
+    #[test]
+    fn compares_strings() {
+        assert!(constant_time_eq("123456", "123456"));
+        assert!(!constant_time_eq("123456", "123457"));
+        assert!(!constant_time_eq("123456", "1234567"));
+        assert!(!constant_time_eq("", "1"));
     }
 
-    let _data = match fs::read_to_string(relative_path) {
-        Ok(data) => return Some(data),
-        Err(_) => return None,
-    };
+    #[test]
+    fn matches_codes_against_their_hash() {
+        let tokens = tokens();
+        let code_hash = tokens.hash("AB12CD");
 
-}
+        assert_ne!(code_hash, "AB12CD");
+        assert!(code_matches(&tokens, "AB12CD", &code_hash));
+        assert!(code_matches(&tokens, " AB12CD\n", &code_hash));
+        assert!(!code_matches(&tokens, "AB12CE", &code_hash));
+        assert!(!code_matches(&tokens, "AB12CD", "AB12CD"));
+    }
 
-fn write_to_file(relative_path: &String, data: &String) -> bool {
-    if !fs::metadata(&relative_path).is_ok() {
-        return false;
+    #[test]
+    fn runs_out_of_attempts() {
+        let mut attempts = 0;
+        assert!(count_wrong_guess(&mut attempts, 3));
+        assert!(count_wrong_guess(&mut attempts, 3));
+        assert!(!count_wrong_guess(&mut attempts, 3));
+        assert_eq!(attempts, 3);
+
+        let mut attempts = u32::MAX;
+        assert!(!count_wrong_guess(&mut attempts, 3));
+        assert_eq!(attempts, u32::MAX);
     }
 
-    match fs::write(&relative_path, data) {
-        Ok(_) => return true,
-        Err(err) => {
-            println!("Failed to writing data to file: {:?}", err);
-            return false;
-        }
+    #[test]
+    fn writes_utc_with_whole_seconds() {
+        let datetime = Utc.with_ymd_and_hms(2024, 3, 31, 1, 30, 0).unwrap();
+        assert_eq!(timestamp(datetime), "2024-03-31T01:30:00Z");
+        assert_eq!(parse_timestamp("2024-03-31T01:30:00Z"), Some(datetime));
+    }
+
+    #[test]
+    fn reads_rfc3339_with_an_offset_as_utc() {
+        let datetime = parse_timestamp("2024-03-31T03:30:00+02:00").unwrap();
+        assert_eq!(timestamp(datetime), "2024-03-31T01:30:00Z");
+    }
+
+    #[test]
+    fn reads_legacy_timestamps_as_local_time() {
+        let local = NaiveDateTime::parse_from_str("2024-07-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let expected = Local.from_local_datetime(&local).earliest().unwrap().with_timezone(&Utc);
+        assert_eq!(parse_timestamp("2024-07-01 12:00:00"), Some(expected));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2024-07-01"), None);
+        assert_eq!(parse_timestamp("2024-13-01 12:00:00"), None);
+    }
+
+    #[test]
+    fn timestamps_sort_in_time_order() {
+        let earlier = Utc.with_ymd_and_hms(2024, 9, 30, 23, 59, 59).unwrap();
+        let later = Utc.with_ymd_and_hms(2024, 10, 1, 0, 0, 0).unwrap();
+        assert!(timestamp(earlier) < timestamp(later));
+        assert!(timestamp(later) < timestamp(later + chrono::Duration::seconds(1)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_secrets_readable_by_the_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("secret_{}", rand::random::<u64>()));
+        write_secret_file(&path, "secret").unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(contents, "secret");
     }
 }